@@ -0,0 +1,25 @@
+create type key_status as enum ('active', 'retiring', 'retired');
+
+create table app_key (
+    id serial primary key,
+    app_id uuid not null,
+    private_key text not null,
+    public_key text not null,
+    status key_status not null default 'active',
+    created_at timestamptz not null default now(),
+    constraint fk_app_id_key
+        foreign key (app_id)
+        references app (id)
+        on delete cascade
+);
+
+create unique index app_key_one_active
+on app_key (app_id)
+where status = 'active';
+
+insert into app_key (app_id, private_key, public_key)
+select id, private_key, public_key from app;
+
+alter table app
+drop column private_key,
+drop column public_key;
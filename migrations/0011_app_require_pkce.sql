@@ -0,0 +1,2 @@
+alter table app
+add column require_pkce boolean not null default false;
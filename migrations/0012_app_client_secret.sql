@@ -0,0 +1,2 @@
+alter table app
+add column client_secret text;
@@ -0,0 +1,5 @@
+alter table app
+add column access_ttl integer not null default 300,
+add column refresh_ttl integer not null default 259200,
+add column session_ttl integer,
+add column idle_timeout integer;
@@ -0,0 +1,2 @@
+alter table app
+add column refresh_grace integer not null default 10;
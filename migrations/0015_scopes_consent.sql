@@ -0,0 +1,17 @@
+alter table app
+add column scopes text[] not null default '{profile}';
+
+-- existing apps have always received the full user
+update app
+set scopes = '{profile,discord,steam,admin}';
+
+create table consent (
+    app_id uuid not null,
+    user_id integer not null,
+    scopes text[] not null,
+    primary key (app_id, user_id),
+    constraint fk_user_consent
+        foreign key (app_id, user_id)
+        references users (app_id, user_id)
+        on delete cascade
+);
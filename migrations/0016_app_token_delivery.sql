@@ -0,0 +1,4 @@
+create type token_delivery as enum ('cookie', 'body');
+
+alter table app
+add column token_delivery token_delivery not null default 'cookie';
@@ -0,0 +1,22 @@
+-- google subject ids, picture urls and names don't fit the original sizes
+alter type Account rename to account_old;
+
+create type Account as (
+    id varchar(256),
+    avatar varchar(256),
+    username varchar(64)
+);
+
+alter table users
+alter column discord type Account using
+    case when discord is null then null
+    else row((discord).id, (discord).avatar, (discord).username)::Account end,
+alter column steam type Account using
+    case when steam is null then null
+    else row((steam).id, (steam).avatar, (steam).username)::Account end;
+
+drop type account_old;
+
+-- like the other accounts, an unlinked google account is a row of nulls
+alter table users
+add column google Account not null default row(null, null, null)::Account;
@@ -0,0 +1,2 @@
+alter table users
+add column github Account not null default row(null, null, null)::Account;
@@ -0,0 +1,39 @@
+create type provider_kind as enum ('oidc', 'oauth2');
+
+create table provider (
+    id serial primary key,
+    slug varchar(32) not null unique,
+    name varchar(64) not null,
+    kind provider_kind not null,
+    -- oidc providers fill in missing endpoints from the issuer's discovery document
+    issuer text,
+    auth_url text,
+    token_url text,
+    userinfo_url text,
+    jwks_url text,
+    client_id text not null,
+    client_secret text not null,
+    scopes text not null default '',
+    id_claim text not null default 'sub',
+    username_claim text not null default 'name',
+    avatar_claim text not null default 'picture',
+    created_at timestamptz not null default now()
+);
+
+create table external_account (
+    app_id uuid not null,
+    user_id integer not null,
+    provider_id integer not null,
+    subject varchar(256) not null,
+    username varchar(256),
+    avatar text,
+    primary key (app_id, provider_id, subject),
+    constraint fk_user_external_account
+        foreign key (app_id, user_id)
+        references users (app_id, user_id)
+        on delete cascade,
+    constraint fk_provider_external_account
+        foreign key (provider_id)
+        references provider (id)
+        on delete cascade
+);
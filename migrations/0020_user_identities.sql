@@ -0,0 +1,79 @@
+create table user_identities (
+    app_id uuid not null,
+    user_id integer not null,
+    -- a built-in provider's name or a registered provider's slug
+    provider varchar(32) not null,
+    subject varchar(256) not null,
+    username varchar(256),
+    avatar text,
+    created_at timestamptz not null default now(),
+    updated_at timestamptz not null default now(),
+    primary key (app_id, provider, subject),
+    constraint fk_user_identity
+        foreign key (app_id, user_id)
+        references users (app_id, user_id)
+        on delete cascade
+);
+
+create index user_identities_user
+on user_identities (app_id, user_id);
+
+create temporary table legacy_accounts as
+select app_id, user_id, 'discord' as provider, (discord).id as subject,
+    (discord).username as username, (discord).avatar as avatar
+from users where (discord).id is not null
+union all
+select app_id, user_id, 'steam', (steam).id, (steam).username, (steam).avatar
+from users where (steam).id is not null
+union all
+select app_id, user_id, 'google', (google).id, (google).username, (google).avatar
+from users where (google).id is not null
+union all
+select app_id, user_id, 'github', (github).id, (github).username, (github).avatar
+from users where (github).id is not null;
+
+-- nothing kept account ids unique since 0007, so the oldest user keeps a duplicated account
+insert into user_identities (app_id, user_id, provider, subject, username, avatar)
+select distinct on (app_id, provider, subject)
+    app_id, user_id, provider, subject, username, avatar
+from legacy_accounts
+order by app_id, provider, subject, user_id;
+
+-- the newer ones are listed for `merge-users <app_id> <kept_user_id> <user_id>`,
+-- which removes their row along with the merged user
+create table user_identity_conflicts (
+    app_id uuid not null,
+    user_id integer not null,
+    provider varchar(32) not null,
+    subject varchar(256) not null,
+    kept_user_id integer not null,
+    primary key (app_id, user_id, provider),
+    constraint fk_user_identity_conflict
+        foreign key (app_id, user_id)
+        references users (app_id, user_id)
+        on delete cascade
+);
+
+insert into user_identity_conflicts (app_id, user_id, provider, subject, kept_user_id)
+select a.app_id, a.user_id, a.provider, a.subject, i.user_id
+from legacy_accounts a
+join user_identities i
+    on i.app_id = a.app_id and i.provider = a.provider and i.subject = a.subject
+where i.user_id <> a.user_id;
+
+drop table legacy_accounts;
+
+insert into user_identities (app_id, user_id, provider, subject, username, avatar)
+select e.app_id, e.user_id, p.slug, e.subject, e.username, e.avatar
+from external_account e
+join provider p on p.id = e.provider_id;
+
+drop table external_account;
+
+alter table users
+drop column discord,
+drop column steam,
+drop column google,
+drop column github;
+
+drop type Account;
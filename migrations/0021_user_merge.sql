@@ -0,0 +1,15 @@
+-- where users merged into another one went, so apps can move their own data over
+create table user_merge (
+    app_id uuid not null,
+    old_user_id integer not null,
+    user_id integer not null,
+    merged_at timestamptz not null default now(),
+    primary key (app_id, old_user_id),
+    constraint fk_user_merge
+        foreign key (app_id, user_id)
+        references users (app_id, user_id)
+        on delete cascade
+);
+
+create index user_merge_merged_at
+on user_merge (app_id, merged_at);
@@ -0,0 +1,14 @@
+-- apps registered under their own application at a provider, used over the server wide one
+create table app_provider_credentials (
+    app_id uuid not null,
+    provider varchar(32) not null,
+    -- steam has no client id, its web api key goes in client_secret
+    client_id text,
+    client_secret text not null,
+    created_at timestamptz not null default now(),
+    primary key (app_id, provider),
+    constraint fk_app_id_provider_credentials
+        foreign key (app_id)
+        references app (id)
+        on delete cascade
+);
@@ -0,0 +1,7 @@
+alter table app
+add column providers text[] not null default '{discord,steam,google,github}',
+add column logo_url text;
+
+-- existing apps keep every provider they could sign in with so far
+update app
+set providers = providers || array(select slug from provider order by name);
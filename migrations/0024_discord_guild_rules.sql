@@ -0,0 +1,31 @@
+-- extra discord scopes requested for the app, 'guilds' and 'guilds.members.read'
+alter table app
+add column discord_scopes text[] not null default '{}';
+
+-- every rule of an app has to pass for a discord login to be admitted
+create table discord_rule (
+    id serial primary key,
+    app_id uuid not null,
+    guild_id varchar(32) not null,
+    -- unset, membership of the guild is enough
+    role_id varchar(32),
+    created_at timestamptz not null default now(),
+    constraint fk_app_id_discord_rule
+        foreign key (app_id)
+        references app (id)
+        on delete cascade
+);
+
+-- the roles a discord account had in the guilds of the app's rules at its last login
+create table discord_guild_roles (
+    app_id uuid not null,
+    subject varchar(256) not null,
+    guild_id varchar(32) not null,
+    roles text[] not null,
+    updated_at timestamptz not null default now(),
+    primary key (app_id, subject, guild_id),
+    constraint fk_app_id_discord_guild_roles
+        foreign key (app_id)
+        references app (id)
+        on delete cascade
+);
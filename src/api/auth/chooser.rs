@@ -0,0 +1,46 @@
+use std::str::FromStr;
+
+use axum::extract::{Path, Query, RawQuery, State};
+use axum::routing::get;
+use axum::Router;
+use serde::Deserialize;
+use sqlx::types::Uuid;
+
+use crate::api::auth::templates::Chooser;
+use crate::api::auth::upstream::enabled_providers;
+use crate::db::app::{get_app, validate_redirect_uri};
+use crate::error::{Error, Result};
+use crate::state::AppState;
+
+pub fn routes() -> Router<AppState> {
+    Router::new().route("/:app_id/login", get(login_page))
+}
+
+#[derive(Deserialize)]
+struct ChooserQuery {
+    redirect_uri: String,
+}
+
+/// The hosted login page, offering the providers enabled for the app. Takes the same
+/// parameters as the provider logins and hands them on to the one the user picks.
+async fn login_page(
+    Path(app_id): Path<String>,
+    State(state): State<AppState>,
+    Query(query): Query<ChooserQuery>,
+    RawQuery(raw_query): RawQuery,
+) -> Result<Chooser> {
+    let uuid = Uuid::from_str(&app_id).map_err(|_| Error::UuidFail)?;
+
+    // checked here too so a bad client never gets to show the page
+    validate_redirect_uri(&state.pg, uuid, &query.redirect_uri).await?;
+
+    let app = get_app(&state.pg, uuid).await?;
+
+    Ok(Chooser {
+        providers: enabled_providers(&state.pg, &app.providers).await?,
+        app_id,
+        app_name: app.name,
+        logo_url: app.logo_url,
+        query: raw_query.unwrap_or_default(),
+    })
+}
@@ -0,0 +1,214 @@
+use std::str::FromStr;
+
+use axum::extract::{Path, Query, State};
+use axum::response::Redirect;
+use axum::routing::get;
+use axum::{Form, Router};
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use redis::Commands;
+use serde::{Deserialize, Serialize};
+use sqlx::types::Uuid;
+
+use crate::api::auth::templates::Consent;
+use crate::api::auth::{client_redirect, AuthCode, LoginState};
+use crate::db::app::get_app;
+use crate::db::consent::{get_consent, grant_consent};
+use crate::error::{Error, Result};
+use crate::state::AppState;
+
+/// Scopes an app can register, with what they share as shown on the consent page.
+pub const SCOPES: [(&str, &str); 6] = [
+    ("profile", "your name and avatar"),
+    ("discord", "your Discord account"),
+    ("steam", "your Steam account"),
+    ("google", "your Google account"),
+    ("github", "your GitHub account"),
+    ("admin", "whether you are an administrator"),
+];
+
+pub fn routes() -> Router<AppState> {
+    Router::new().route("/:app_id/consent", get(consent_page).post(decide))
+}
+
+/// A login waiting on the user to agree to new scopes.
+#[derive(Serialize, Deserialize)]
+struct PendingConsent {
+    user_id: i32,
+    scopes: Vec<String>,
+    login: LoginState,
+}
+
+/// Finishes a provider login. Asks for consent when the login requests scopes the user
+/// hasn't granted the app yet, then hands the client a one-time code.
+/// Logins without a `scope` request every scope the app registered.
+pub async fn complete_login(
+    state: &mut AppState,
+    user_id: i32,
+    mut login: LoginState,
+) -> Result<Redirect> {
+    let app_id = login.app_id.clone();
+    let uuid = Uuid::from_str(&app_id).map_err(|_| Error::UuidFail)?;
+
+    let app = get_app(&state.pg, uuid).await?;
+
+    // scopes the app hasn't registered are left out rather than failing the login
+    let scopes: Vec<String> = match &login.scope {
+        Some(scope) => scope
+            .split(' ')
+            .filter(|s| app.scopes.iter().any(|registered| registered == s))
+            .map(str::to_string)
+            .collect(),
+        None => app.scopes.clone(),
+    };
+
+    let openid = login
+        .scope
+        .as_ref()
+        .is_some_and(|scope| scope.split(' ').any(|s| s == "openid"));
+
+    let granted = openid.then(|| "openid".to_string()).into_iter();
+    login.scope = Some(granted.chain(scopes.clone()).collect::<Vec<_>>().join(" "));
+
+    let consented = get_consent(&state.pg, uuid, user_id).await?;
+
+    if scopes.iter().all(|scope| consented.contains(scope)) {
+        return issue_code(state, user_id, login);
+    }
+
+    let id: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect();
+
+    let pending = PendingConsent {
+        user_id,
+        scopes,
+        login,
+    };
+
+    let key = format!("{app_id}:consent:{id}");
+
+    state
+        .redis
+        .set::<_, _, ()>(
+            &key,
+            serde_json::to_string(&pending).map_err(|_| Error::RedisSetFail)?,
+        )
+        .map_err(|_| Error::RedisSetFail)?;
+
+    state
+        .redis
+        .expire::<_, ()>(&key, 60 * 5)
+        .map_err(|_| Error::RedisExpireFail)?;
+
+    Ok(Redirect::to(&format!("/api/auth/{app_id}/consent?id={id}")))
+}
+
+/// Stores a one-time code for the login and redirects back to the client with it.
+fn issue_code(state: &mut AppState, user_id: i32, login: LoginState) -> Result<Redirect> {
+    let code: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect();
+
+    let code_key = format!("{}:code:{code}", login.app_id);
+    let ttl = 30;
+
+    let redirect_uri = login.redirect_uri.clone();
+    let client_state = login.client_state.clone();
+
+    let auth_code = AuthCode::new(user_id, login)?;
+
+    state
+        .redis
+        .set::<_, _, ()>(
+            &code_key,
+            serde_json::to_string(&auth_code).map_err(|_| Error::RedisSetFail)?,
+        )
+        .map_err(|_| Error::RedisSetFail)?;
+
+    state
+        .redis
+        .expire::<_, ()>(&code_key, ttl)
+        .map_err(|_| Error::RedisExpireFail)?;
+
+    Ok(Redirect::to(&client_redirect(
+        &redirect_uri,
+        &[("code", &code)],
+        client_state.as_deref(),
+    )))
+}
+
+fn get_pending(state: &mut AppState, app_id: &str, id: &str) -> Result<PendingConsent> {
+    let pending: Option<String> = state
+        .redis
+        .get(format!("{app_id}:consent:{id}"))
+        .map_err(|_| Error::RedisGetFail)?;
+
+    serde_json::from_str(&pending.ok_or(Error::RedisGetEmpty)?).map_err(|_| Error::RedisGetFail)
+}
+
+#[derive(Deserialize)]
+struct ConsentQuery {
+    id: String,
+}
+
+async fn consent_page(
+    Path(app_id): Path<String>,
+    State(mut state): State<AppState>,
+    Query(query): Query<ConsentQuery>,
+) -> Result<Consent> {
+    let pending = get_pending(&mut state, &app_id, &query.id)?;
+
+    let uuid = Uuid::from_str(&app_id).map_err(|_| Error::UuidFail)?;
+
+    let scopes = SCOPES
+        .iter()
+        .filter(|(name, _)| pending.scopes.iter().any(|scope| scope == name))
+        .map(|(_, description)| *description)
+        .collect();
+
+    Ok(Consent {
+        app_name: get_app(&state.pg, uuid).await?.name,
+        app_id,
+        id: query.id,
+        scopes,
+    })
+}
+
+#[derive(Deserialize)]
+struct ConsentForm {
+    id: String,
+    decision: String,
+}
+
+/// Remembers the user's consent and continues the login, or tells the client it was denied.
+async fn decide(
+    Path(app_id): Path<String>,
+    State(mut state): State<AppState>,
+    Form(form): Form<ConsentForm>,
+) -> Result<Redirect> {
+    let pending = get_pending(&mut state, &app_id, &form.id)?;
+
+    state
+        .redis
+        .del::<_, ()>(format!("{app_id}:consent:{}", form.id))
+        .map_err(|_| Error::RedisDelFail)?;
+
+    if form.decision != "allow" {
+        return Ok(Redirect::to(&client_redirect(
+            &pending.login.redirect_uri,
+            &[("error", "access_denied")],
+            pending.login.client_state.as_deref(),
+        )));
+    }
+
+    let uuid = Uuid::from_str(&app_id).map_err(|_| Error::UuidFail)?;
+
+    grant_consent(&state.pg, uuid, pending.user_id, &pending.scopes).await?;
+
+    issue_code(&mut state, pending.user_id, pending.login)
+}
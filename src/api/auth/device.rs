@@ -0,0 +1,467 @@
+use std::str::FromStr;
+
+use axum::extract::{Path, Query, State};
+use axum::response::{IntoResponse, Redirect, Response};
+use axum::routing::{get, post};
+use axum::{Form, Json, Router};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use http::header::CACHE_CONTROL;
+use http::HeaderMap;
+use oauth2::url::form_urlencoded::Serializer;
+use openssl::sha::sha256;
+use rand::distributions::{Alphanumeric, Slice};
+use rand::{thread_rng, Rng};
+use redis::Commands;
+use serde::{Deserialize, Serialize};
+use sqlx::types::Uuid;
+
+use crate::api::auth::oauth::authenticate_client;
+use crate::api::auth::redeem_code;
+use crate::api::auth::templates::{Device, DeviceDone};
+use crate::api::auth::upstream::{enabled_providers, login_path};
+use crate::db::app::get_app;
+use crate::error::{Error, Result};
+use crate::jwt::{issuer, now};
+use crate::state::AppState;
+
+pub const DEVICE_CODE_GRANT: &str = "urn:ietf:params:oauth:grant-type:device_code";
+
+/// How long a device has to be approved, in seconds.
+const DEVICE_CODE_TTL: usize = 60 * 10;
+
+/// The minimum time between token endpoint polls, in seconds.
+const POLL_INTERVAL: usize = 5;
+
+/// User codes avoid vowels and look-alike characters so they're easy to type and can't
+/// spell anything.
+const USER_CODE_CHARS: [char; 20] = [
+    'B', 'C', 'D', 'F', 'G', 'H', 'J', 'K', 'L', 'M', 'N', 'P', 'Q', 'R', 'S', 'T', 'V', 'W', 'X',
+    'Z',
+];
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/:app_id/device_authorization", post(device_authorization))
+        .route("/:app_id/device", get(device_page))
+        .route("/:app_id/device/verify", get(verify))
+        .route("/:app_id/device/callback", get(callback))
+        .route("/:app_id/device/confirm", post(confirm))
+}
+
+/// A pending device login, stored under its `device_code` until it is redeemed or expires.
+#[derive(Serialize, Deserialize)]
+pub struct DeviceGrant {
+    pub user_code: String,
+    pub scope: Option<String>,
+    /// PKCE verifier for the provider login started from the verification page.
+    pub code_verifier: Option<String>,
+    /// Set once the user has signed in on the verification page.
+    pub user_id: Option<i32>,
+    pub auth_time: Option<usize>,
+    /// Given to the browser that signed in, to approve the device with.
+    #[serde(default)]
+    pub confirm_token: Option<String>,
+    /// Set once the signed in user confirmed the code is their device's.
+    #[serde(default)]
+    pub approved: bool,
+    /// Set when the user declined the consent screen.
+    #[serde(default)]
+    pub denied: bool,
+    pub last_poll: usize,
+    pub expires_at: usize,
+}
+
+/// Where the provider login started from the verification page returns to.
+pub fn device_callback_uri(app_id: &str) -> String {
+    format!("{}/device/callback", issuer(app_id))
+}
+
+#[derive(Deserialize)]
+struct DeviceAuthorizationForm {
+    client_id: Option<String>,
+    client_secret: Option<String>,
+    scope: Option<String>,
+}
+
+#[derive(Serialize)]
+struct DeviceAuthorization {
+    device_code: String,
+    user_code: String,
+    verification_uri: String,
+    verification_uri_complete: String,
+    expires_in: usize,
+    interval: usize,
+}
+
+/// RFC 8628 device authorization endpoint.
+async fn device_authorization(
+    Path(app_id): Path<String>,
+    State(mut state): State<AppState>,
+    headers: HeaderMap,
+    Form(form): Form<DeviceAuthorizationForm>,
+) -> Result<impl IntoResponse> {
+    authenticate_client(
+        &state.pg,
+        &app_id,
+        &headers,
+        form.client_id.as_deref(),
+        form.client_secret.as_deref(),
+    )
+    .await?;
+
+    let device_code: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(40)
+        .map(char::from)
+        .collect();
+
+    let user_code: String = thread_rng()
+        .sample_iter(Slice::new(&USER_CODE_CHARS).map_err(|_| Error::AuthInvalidParams)?)
+        .take(8)
+        .collect();
+
+    let user_code = format!("{}-{}", &user_code[..4], &user_code[4..]);
+
+    let now = now()?;
+
+    let grant = DeviceGrant {
+        user_code: user_code.clone(),
+        scope: form.scope,
+        code_verifier: None,
+        user_id: None,
+        auth_time: None,
+        confirm_token: None,
+        approved: false,
+        denied: false,
+        last_poll: 0,
+        expires_at: now + DEVICE_CODE_TTL,
+    };
+
+    store_grant(&mut state, &app_id, &device_code, &grant)?;
+
+    let user_code_key = format!("{app_id}:user_code:{user_code}");
+
+    state
+        .redis
+        .set::<_, _, ()>(&user_code_key, &device_code)
+        .map_err(|_| Error::RedisSetFail)?;
+
+    state
+        .redis
+        .expire::<_, ()>(&user_code_key, DEVICE_CODE_TTL as i64)
+        .map_err(|_| Error::RedisExpireFail)?;
+
+    let verification_uri = format!("{}/device", issuer(&app_id));
+
+    let mut complete = Serializer::new(String::new());
+    complete.append_pair("user_code", &user_code);
+
+    Ok((
+        [(CACHE_CONTROL, "no-store")],
+        Json(DeviceAuthorization {
+            device_code,
+            verification_uri_complete: format!("{verification_uri}?{}", complete.finish()),
+            verification_uri,
+            user_code,
+            expires_in: DEVICE_CODE_TTL,
+            interval: POLL_INTERVAL,
+        }),
+    ))
+}
+
+#[derive(Deserialize)]
+struct DeviceQuery {
+    user_code: Option<String>,
+}
+
+/// The hosted page where the user enters the code shown on their device.
+async fn device_page(
+    Path(app_id): Path<String>,
+    State(state): State<AppState>,
+    Query(query): Query<DeviceQuery>,
+) -> Result<Device> {
+    let uuid = Uuid::from_str(&app_id).map_err(|_| Error::UuidFail)?;
+
+    let app = get_app(&state.pg, uuid).await?;
+
+    Ok(Device {
+        providers: enabled_providers(&state.pg, &app.providers).await?,
+        app_name: app.name,
+        app_id,
+        user_code: query.user_code.unwrap_or_default(),
+        confirm_token: None,
+    })
+}
+
+#[derive(Deserialize)]
+struct VerifyQuery {
+    user_code: String,
+    provider: String,
+}
+
+/// Checks the entered code and starts the provider login, with the verification page
+/// acting as the client.
+async fn verify(
+    Path(app_id): Path<String>,
+    State(mut state): State<AppState>,
+    Query(query): Query<VerifyQuery>,
+) -> Result<Redirect> {
+    let login_path = login_path(&state.pg, &query.provider, &app_id)
+        .await?
+        .ok_or(Error::AuthInvalidParams)?;
+
+    let user_code = normalize_user_code(&query.user_code).ok_or(Error::AuthInvalidParams)?;
+
+    let (device_code, mut grant) = grant_by_user_code(&mut state, &app_id, &user_code)?;
+
+    if grant.user_id.is_some() || grant.denied {
+        return Err(Error::AuthInvalidParams);
+    }
+
+    let code_verifier: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(64)
+        .map(char::from)
+        .collect();
+
+    let code_challenge = URL_SAFE_NO_PAD.encode(sha256(code_verifier.as_bytes()));
+
+    grant.code_verifier = Some(code_verifier);
+
+    store_grant(&mut state, &app_id, &device_code, &grant)?;
+
+    let mut login = Serializer::new(String::new());
+
+    login.append_pair("redirect_uri", &device_callback_uri(&app_id));
+    login.append_pair("state", &user_code);
+    login.append_pair("code_challenge", &code_challenge);
+    login.append_pair("code_challenge_method", "S256");
+
+    if let Some(scope) = &grant.scope {
+        login.append_pair("scope", scope);
+    }
+
+    Ok(Redirect::to(&format!("{login_path}?{}", login.finish())))
+}
+
+#[derive(Deserialize)]
+struct CallbackQuery {
+    code: Option<String>,
+    error: Option<String>,
+    state: String,
+}
+
+/// Redeems the provider login for the device, then asks the user to check the code and app
+/// before the device is approved, as links to the verification page can come from anyone.
+async fn callback(
+    Path(app_id): Path<String>,
+    State(mut state): State<AppState>,
+    Query(query): Query<CallbackQuery>,
+) -> Result<Response> {
+    let (device_code, mut grant) = grant_by_user_code(&mut state, &app_id, &query.state)?;
+
+    let code_verifier = grant.code_verifier.take().ok_or(Error::AuthMissingState)?;
+
+    let (Some(code), None) = (query.code, query.error) else {
+        grant.denied = true;
+
+        return Ok(finish(&mut state, &app_id, &device_code, &grant)?.into_response());
+    };
+
+    let (user, auth_code) = redeem_code(
+        &mut state,
+        &app_id,
+        &code,
+        Some(&device_callback_uri(&app_id)),
+        Some(&code_verifier),
+    )
+    .await?;
+
+    let confirm_token: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect();
+
+    grant.user_id = Some(user.user_id);
+    grant.auth_time = Some(auth_code.auth_time);
+    grant.scope = auth_code.scope;
+    grant.confirm_token = Some(confirm_token.clone());
+
+    store_grant(&mut state, &app_id, &device_code, &grant)?;
+
+    let uuid = Uuid::from_str(&app_id).map_err(|_| Error::UuidFail)?;
+
+    Ok(Device {
+        app_name: get_app(&state.pg, uuid).await?.name,
+        app_id,
+        user_code: grant.user_code,
+        providers: Vec::new(),
+        confirm_token: Some(confirm_token),
+    }
+    .into_response())
+}
+
+#[derive(Deserialize)]
+struct ConfirmForm {
+    user_code: String,
+    confirm_token: String,
+    decision: String,
+}
+
+/// Approves or denies the device once the signed in user has checked its code, the device
+/// picking the outcome up on its next poll.
+async fn confirm(
+    Path(app_id): Path<String>,
+    State(mut state): State<AppState>,
+    Form(form): Form<ConfirmForm>,
+) -> Result<DeviceDone> {
+    let (device_code, mut grant) = grant_by_user_code(&mut state, &app_id, &form.user_code)?;
+
+    if grant.confirm_token.take() != Some(form.confirm_token) {
+        return Err(Error::AuthInvalidParams);
+    }
+
+    match form.decision.as_str() {
+        "approve" => grant.approved = true,
+        _ => grant.denied = true,
+    }
+
+    finish(&mut state, &app_id, &device_code, &grant)
+}
+
+/// Saves the outcome of the verification and retires its user code.
+fn finish(
+    state: &mut AppState,
+    app_id: &str,
+    device_code: &str,
+    grant: &DeviceGrant,
+) -> Result<DeviceDone> {
+    store_grant(state, app_id, device_code, grant)?;
+
+    state
+        .redis
+        .del::<_, ()>(format!("{app_id}:user_code:{}", grant.user_code))
+        .map_err(|_| Error::RedisDelFail)?;
+
+    Ok(DeviceDone {
+        approved: grant.approved,
+    })
+}
+
+/// Outcome of polling the token endpoint with a `device_code`.
+pub enum DevicePoll {
+    Approved(DeviceGrant),
+    Pending,
+}
+
+/// Checks on a device login for the token endpoint, consuming it once approved.
+pub fn poll_device_code(
+    state: &mut AppState,
+    app_id: &str,
+    device_code: &str,
+) -> Result<DevicePoll> {
+    let key = format!("{app_id}:device:{device_code}");
+
+    let grant: Option<String> = state.redis.get(&key).map_err(|_| Error::RedisGetFail)?;
+    let mut grant: DeviceGrant = serde_json::from_str(&grant.ok_or(Error::OAuthExpiredToken)?)
+        .map_err(|_| Error::RedisGetFail)?;
+
+    if grant.denied {
+        state
+            .redis
+            .del::<_, ()>(&key)
+            .map_err(|_| Error::RedisDelFail)?;
+
+        return Err(Error::OAuthAccessDenied);
+    }
+
+    if grant.approved {
+        state
+            .redis
+            .del::<_, ()>(&key)
+            .map_err(|_| Error::RedisDelFail)?;
+
+        return Ok(DevicePoll::Approved(grant));
+    }
+
+    let now = now()?;
+    let last_poll = grant.last_poll;
+
+    grant.last_poll = now;
+
+    store_grant(state, app_id, device_code, &grant)?;
+
+    if now - last_poll < POLL_INTERVAL {
+        return Err(Error::OAuthSlowDown);
+    }
+
+    Ok(DevicePoll::Pending)
+}
+
+fn grant_by_user_code(
+    state: &mut AppState,
+    app_id: &str,
+    user_code: &str,
+) -> Result<(String, DeviceGrant)> {
+    let device_code: Option<String> = state
+        .redis
+        .get(format!("{app_id}:user_code:{user_code}"))
+        .map_err(|_| Error::RedisGetFail)?;
+    let device_code = device_code.ok_or(Error::RedisGetEmpty)?;
+
+    let grant: Option<String> = state
+        .redis
+        .get(format!("{app_id}:device:{device_code}"))
+        .map_err(|_| Error::RedisGetFail)?;
+    let grant = serde_json::from_str(&grant.ok_or(Error::RedisGetEmpty)?)
+        .map_err(|_| Error::RedisGetFail)?;
+
+    Ok((device_code, grant))
+}
+
+/// Saves the grant, keeping the expiry it was created with.
+fn store_grant(
+    state: &mut AppState,
+    app_id: &str,
+    device_code: &str,
+    grant: &DeviceGrant,
+) -> Result<()> {
+    let ttl = grant.expires_at.saturating_sub(now()?);
+
+    if ttl == 0 {
+        return Err(Error::OAuthExpiredToken);
+    }
+
+    let key = format!("{app_id}:device:{device_code}");
+
+    state
+        .redis
+        .set::<_, _, ()>(
+            &key,
+            serde_json::to_string(grant).map_err(|_| Error::RedisSetFail)?,
+        )
+        .map_err(|_| Error::RedisSetFail)?;
+
+    state
+        .redis
+        .expire::<_, ()>(&key, ttl as i64)
+        .map_err(|_| Error::RedisExpireFail)?;
+
+    Ok(())
+}
+
+/// Accepts codes typed in lowercase, without the dash or with extra spaces.
+fn normalize_user_code(user_code: &str) -> Option<String> {
+    let chars: String = user_code
+        .chars()
+        .filter(char::is_ascii_alphabetic)
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+
+    if chars.len() != 8 {
+        return None;
+    }
+
+    Some(format!("{}-{}", &chars[..4], &chars[4..]))
+}
@@ -1,22 +1,27 @@
-use std::str::FromStr;
-
-use crate::db::app::validate_redirect_uri;
-use crate::db::user::{create_user, get_user, Account};
+use crate::api::auth::identity::{
+    app_credentials, begin_login, finish_login, Callback, IdentityProvider, LoginQuery,
+};
+use crate::db::app::get_app;
+use crate::db::discord::{get_discord_rules, set_guild_roles, DiscordRule};
+use crate::db::user::Account;
 use crate::error::{Error, Result};
-use crate::state::AppState;
+use crate::state::{discord_client, AppState};
 use axum::extract::{Path, Query};
 use axum::routing::get;
 use axum::Router;
 use axum::{extract::State, response::Redirect};
+use http::header::AUTHORIZATION;
+use http::StatusCode;
+use oauth2::basic::BasicClient;
 use oauth2::reqwest::async_http_client;
 use oauth2::TokenResponse;
 use oauth2::{AuthorizationCode, CsrfToken, Scope};
-use rand::distributions::Alphanumeric;
-use rand::thread_rng;
-use rand::Rng;
-use redis::Commands;
 use serde::Deserialize;
 use sqlx::types::Uuid;
+use std::collections::BTreeMap;
+use std::env;
+use std::str::FromStr;
+use tower_cookies::Cookies;
 
 pub fn routes() -> Router<AppState> {
     Router::new()
@@ -24,107 +29,259 @@ pub fn routes() -> Router<AppState> {
         .route("/redirect", get(auth_redirect))
 }
 
-#[derive(Deserialize)]
-struct LoginQuery {
-    redirect_uri: String,
-}
+struct Discord;
 
-async fn auth_login(
-    Path(app_id): Path<String>,
-    Query(query): Query<LoginQuery>,
-    State(mut state): State<AppState>,
-) -> Result<Redirect> {
-    let uuid = Uuid::from_str(&app_id).map_err(|_| Error::UuidFail)?;
-
-    validate_redirect_uri(&state.pg, uuid, &query.redirect_uri).await?;
-
-    let (auth_url, _) = state
-        .oauth
-        .authorize_url(CsrfToken::new_random)
-        .add_scope(Scope::new("identify".to_string()))
-        .url();
-
-    let (_, app_state) = auth_url
-        .query_pairs()
-        .find(|(key, _)| key == "state")
-        .ok_or(Error::AuthMissingState)?;
-
-    state
-        .redis
-        .set(
-            app_state.as_ref(),
-            format!("{};{}", app_id, query.redirect_uri),
-        )
-        .map_err(|_| Error::RedisSetFail)?;
-
-    state
-        .redis
-        .expire(app_state.as_ref(), 30)
-        .map_err(|_| Error::RedisExpireFail)?;
-
-    Ok(Redirect::to(auth_url.as_ref()))
+/// The app's own Discord application when it registered one, the server's otherwise.
+async fn client(state: &AppState, app_id: &str) -> Result<BasicClient> {
+    Ok(match app_credentials(state, app_id, "discord").await? {
+        Some(credentials) => discord_client(
+            credentials.client_id.ok_or(Error::AuthInvalidParams)?,
+            credentials.client_secret,
+        ),
+        None => state.oauth.clone(),
+    })
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Deserialize)]
 struct AuthRequest {
     code: String,
-    state: String,
 }
 
-async fn auth_redirect(
-    Query(query): Query<AuthRequest>,
-    State(mut state): State<AppState>,
-) -> Result<Redirect> {
-    let token = state
-        .oauth
-        .exchange_code(AuthorizationCode::new(query.code))
-        .request_async(async_http_client)
-        .await
-        .map_err(|_| Error::AuthTokenExchangeFail)?;
+impl IdentityProvider for Discord {
+    type Callback = AuthRequest;
 
-    let client = reqwest::Client::new();
-    let user: Account = client
-        .get("https://discordapp.com/api/users/@me")
-        .bearer_auth(token.access_token().secret())
+    const STATE_TTL: i64 = 30;
+
+    fn name(&self) -> &str {
+        "discord"
+    }
+
+    async fn authorize_url(&self, state: &AppState, app_id: &str, key: &str) -> Result<String> {
+        let uuid = Uuid::from_str(app_id).map_err(|_| Error::UuidFail)?;
+        let app = get_app(&state.pg, uuid).await?;
+
+        let (auth_url, _) = client(state, app_id)
+            .await?
+            .authorize_url(|| CsrfToken::new(key.to_string()))
+            .add_scope(Scope::new("identify".to_string()))
+            .add_scopes(app.discord_scopes.into_iter().map(Scope::new))
+            .url();
+
+        Ok(auth_url.to_string())
+    }
+
+    async fn identify(
+        &self,
+        state: &AppState,
+        app_id: &str,
+        callback: AuthRequest,
+        _: &str,
+    ) -> Result<Account> {
+        let token = client(state, app_id)
+            .await?
+            .exchange_code(AuthorizationCode::new(callback.code))
+            .request_async(async_http_client)
+            .await
+            .map_err(|_| Error::AuthTokenExchangeFail)?;
+
+        let client = reqwest::Client::new();
+        let access_token = token.access_token().secret();
+
+        let user: Account = client
+            .get(format!("{}/users/@me", api_url()))
+            .bearer_auth(access_token)
+            .send()
+            .await
+            .map_err(|_| Error::AuthUserFetchFail)?
+            .json()
+            .await
+            .map_err(|_| Error::AuthUserParseFail)?;
+
+        let uuid = Uuid::from_str(app_id).map_err(|_| Error::UuidFail)?;
+        let subject = user.id.as_deref().ok_or(Error::AuthUserParseFail)?;
+        let rules = get_discord_rules(&state.pg, uuid).await?;
+
+        // roles kept under since removed rules go, with nothing to check them against
+        if rules.is_empty() {
+            set_guild_roles(&state.pg, uuid, subject, &BTreeMap::new()).await?;
+
+            return Ok(user);
+        }
+
+        let app = get_app(&state.pg, uuid).await?;
+        let guild_roles = guild_roles(&client, access_token, &app.discord_scopes, &rules).await?;
+
+        if !rules.iter().all(|rule| rule.admits(&guild_roles)) {
+            return Err(Error::OAuthAccessDenied);
+        }
+
+        set_guild_roles(&state.pg, uuid, subject, &guild_roles).await?;
+
+        Ok(user)
+    }
+}
+
+#[derive(Deserialize)]
+struct Guild {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct GuildMember {
+    roles: Vec<String>,
+}
+
+/// The user's roles in the guilds the rules name, leaving out guilds they aren't in. Roles
+/// take the `guilds.members.read` scope, with only `guilds` the guilds come without roles
+/// and with neither nothing is known, so every rule fails.
+async fn guild_roles(
+    client: &reqwest::Client,
+    access_token: &str,
+    scopes: &[String],
+    rules: &[DiscordRule],
+) -> Result<BTreeMap<String, Vec<String>>> {
+    let mut guild_ids: Vec<&str> = rules.iter().map(|rule| rule.guild_id.as_str()).collect();
+    guild_ids.sort();
+    guild_ids.dedup();
+
+    let mut guild_roles = BTreeMap::new();
+
+    if scopes.iter().any(|scope| scope == "guilds.members.read") {
+        for guild_id in guild_ids {
+            let res = client
+                .get(format!("{}/users/@me/guilds/{guild_id}/member", api_url()))
+                .bearer_auth(access_token)
+                .send()
+                .await
+                .map_err(|_| Error::AuthUserFetchFail)?;
+
+            // discord answers 404 for guilds the user isn't a member of
+            if res.status() == StatusCode::NOT_FOUND {
+                continue;
+            }
+
+            let member: GuildMember = res
+                .error_for_status()
+                .map_err(|_| Error::AuthUserFetchFail)?
+                .json()
+                .await
+                .map_err(|_| Error::AuthUserParseFail)?;
+
+            guild_roles.insert(guild_id.to_string(), member.roles);
+        }
+    } else if scopes.iter().any(|scope| scope == "guilds") {
+        let guilds: Vec<Guild> = client
+            .get(format!("{}/users/@me/guilds", api_url()))
+            .bearer_auth(access_token)
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(|_| Error::AuthUserFetchFail)?
+            .json()
+            .await
+            .map_err(|_| Error::AuthUserParseFail)?;
+
+        for guild in guilds {
+            if guild_ids.contains(&guild.id.as_str()) {
+                guild_roles.insert(guild.id, Vec::new());
+            }
+        }
+    }
+
+    Ok(guild_roles)
+}
+
+/// Overridable to sign in and read users against a local stand-in.
+pub fn api_url() -> String {
+    env::var("DISCORD_API_URL").unwrap_or("https://discord.com/api".to_string())
+}
+
+/// Looks up a discord user by id, which takes a bot token rather than the user's own.
+pub async fn fetch_user(
+    client: &reqwest::Client,
+    bot_token: &str,
+    user_id: &str,
+) -> Result<Account> {
+    client
+        .get(format!("{}/users/{user_id}", api_url()))
+        .header(AUTHORIZATION, format!("Bot {bot_token}"))
         .send()
         .await
+        .and_then(|res| res.error_for_status())
         .map_err(|_| Error::AuthUserFetchFail)?
         .json()
         .await
-        .map_err(|_| Error::AuthUserParseFail)?;
+        .map_err(|_| Error::AuthUserParseFail)
+}
 
-    let oauth_state: Option<String> = state
-        .redis
-        .get(query.state)
-        .map_err(|_| Error::RedisGetFail)?;
-    let oauth_state = oauth_state.ok_or(Error::RedisGetEmpty)?;
+async fn auth_login(
+    Path(app_id): Path<String>,
+    Query(query): Query<LoginQuery>,
+    State(mut state): State<AppState>,
+) -> Result<Redirect> {
+    begin_login(&mut state, &Discord, app_id, query).await
+}
 
-    let (app_id, redirect_uri) = oauth_state.split_once(";").ok_or(Error::RedisGetFail)?;
+async fn auth_redirect(
+    Query(callback): Query<Callback<AuthRequest>>,
+    State(mut state): State<AppState>,
+    cookies: Cookies,
+) -> Result<Redirect> {
+    finish_login(&mut state, &Discord, &cookies, callback).await
+}
 
-    let code: String = thread_rng()
-        .sample_iter(&Alphanumeric)
-        .take(32)
-        .map(char::from)
-        .collect();
+#[cfg(test)]
+mod tests {
+    use super::{AuthRequest, Discord};
+    use crate::api::auth::identity::IdentityProvider;
+    use crate::api::auth::stand_in::test_state;
+    use crate::db::app::create_app;
+    use crate::db::user::{create_user, get_user_by_id, Account};
 
-    let code_key = format!("{app_id}:code:{code}");
-    let ttl = 30;
+    #[tokio::test]
+    #[ignore = "needs TEST_POSTGRES_URL"]
+    async fn login_updates_profile() {
+        let state = test_state().await;
+        let app_id = create_app(&state.pg, "discord login".to_string())
+            .await
+            .unwrap();
 
-    let uuid = Uuid::from_str(&app_id).map_err(|_| Error::UuidFail)?;
+        let old = Account {
+            id: Some("3001".to_string()),
+            avatar: Some("old".to_string()),
+            username: Some("old".to_string()),
+        };
 
-    if let None = get_user(&state.pg, uuid, &user.id.as_ref().map_or("", |id| &id)).await? {
-        create_user(&state.pg, uuid, Some(&user), None).await?;
-    }
+        let user_id = create_user(&state.pg, app_id, "discord", &old)
+            .await
+            .unwrap();
 
-    state
-        .redis
-        .set(&code_key, &user.id)
-        .map_err(|_| Error::RedisSetFail)?;
+        let account = Discord
+            .identify(
+                &state,
+                &app_id.to_string(),
+                AuthRequest {
+                    code: "3001".to_string(),
+                },
+                "state",
+            )
+            .await
+            .unwrap();
 
-    state
-        .redis
-        .expire(&code_key, ttl)
-        .map_err(|_| Error::RedisExpireFail)?;
+        assert_eq!(
+            Discord
+                .save_user(&state.pg, app_id, &account)
+                .await
+                .unwrap(),
+            user_id
+        );
 
-    Ok(Redirect::to(format!("{redirect_uri}?code={code}").as_ref()))
+        let discord = get_user_by_id(&state.pg, app_id, user_id)
+            .await
+            .unwrap()
+            .unwrap()
+            .discord;
+        assert_eq!(discord.username.as_deref(), Some("user-3001"));
+        assert_eq!(discord.avatar.as_deref(), Some("avatar-3001"));
+    }
 }
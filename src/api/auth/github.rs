@@ -0,0 +1,123 @@
+use std::env;
+
+use crate::api::auth::identity::{
+    app_credentials, begin_login, finish_login, Callback, IdentityProvider, LoginQuery,
+};
+use crate::db::user::Account;
+use crate::error::{Error, Result};
+use crate::state::{github_app_client, AppState};
+use axum::extract::{Path, Query};
+use axum::routing::get;
+use axum::Router;
+use axum::{extract::State, response::Redirect};
+use oauth2::basic::BasicClient;
+use oauth2::reqwest::async_http_client;
+use oauth2::TokenResponse;
+use oauth2::{AuthorizationCode, CsrfToken, Scope};
+use serde::Deserialize;
+use tower_cookies::Cookies;
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/:app_id/", get(auth_login))
+        .route("/redirect", get(auth_redirect))
+}
+
+#[derive(Deserialize)]
+struct AuthRequest {
+    code: String,
+}
+
+#[derive(Deserialize)]
+struct GithubUser {
+    id: u64,
+    login: String,
+    avatar_url: Option<String>,
+}
+
+struct Github;
+
+/// The app's own GitHub OAuth app when it registered one, the server's otherwise.
+async fn client(state: &AppState, app_id: &str) -> Result<BasicClient> {
+    Ok(match app_credentials(state, app_id, "github").await? {
+        Some(credentials) => github_app_client(
+            credentials.client_id.ok_or(Error::AuthInvalidParams)?,
+            credentials.client_secret,
+        ),
+        None => state
+            .github
+            .clone()
+            .ok_or(Error::AuthProviderUnconfigured)?,
+    })
+}
+
+impl IdentityProvider for Github {
+    type Callback = AuthRequest;
+
+    fn name(&self) -> &str {
+        "github"
+    }
+
+    async fn authorize_url(&self, state: &AppState, app_id: &str, key: &str) -> Result<String> {
+        let (auth_url, _) = client(state, app_id)
+            .await?
+            .authorize_url(|| CsrfToken::new(key.to_string()))
+            .add_scope(Scope::new("read:user".to_string()))
+            .url();
+
+        Ok(auth_url.to_string())
+    }
+
+    async fn identify(
+        &self,
+        state: &AppState,
+        app_id: &str,
+        callback: AuthRequest,
+        _: &str,
+    ) -> Result<Account> {
+        let token = client(state, app_id)
+            .await?
+            .exchange_code(AuthorizationCode::new(callback.code))
+            .request_async(async_http_client)
+            .await
+            .map_err(|_| Error::AuthTokenExchangeFail)?;
+
+        let api_url = env::var("GITHUB_API_URL").unwrap_or("https://api.github.com".to_string());
+
+        // github rejects api requests without a user agent
+        let client = reqwest::Client::new();
+        let user: GithubUser = client
+            .get(format!("{api_url}/user"))
+            .header("User-Agent", "sso-server")
+            .bearer_auth(token.access_token().secret())
+            .send()
+            .await
+            .map_err(|_| Error::AuthUserFetchFail)?
+            .json()
+            .await
+            .map_err(|_| Error::AuthUserParseFail)?;
+
+        Ok(Account {
+            id: Some(user.id.to_string()),
+            avatar: user.avatar_url,
+            username: Some(user.login),
+        })
+    }
+}
+
+async fn auth_login(
+    Path(app_id): Path<String>,
+    Query(query): Query<LoginQuery>,
+    State(mut state): State<AppState>,
+) -> Result<Redirect> {
+    // github may ask for a second factor, so it keeps the default five minutes
+    begin_login(&mut state, &Github, app_id, query).await
+}
+
+async fn auth_redirect(
+    Query(callback): Query<Callback<AuthRequest>>,
+    State(mut state): State<AppState>,
+    cookies: Cookies,
+) -> Result<Redirect> {
+    finish_login(&mut state, &Github, &cookies, callback).await
+}
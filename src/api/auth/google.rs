@@ -0,0 +1,187 @@
+use std::env;
+
+use axum::extract::{Path, Query, State};
+use axum::response::Redirect;
+use axum::routing::get;
+use axum::Router;
+use oauth2::url::form_urlencoded::Serializer;
+use serde::Deserialize;
+use tower_cookies::Cookies;
+
+use crate::api::auth::identity::{
+    app_credentials, begin_login, finish_login, Callback, IdentityProvider, LoginQuery,
+};
+use crate::api::auth::upstream::verify_id_token;
+use crate::db::user::Account;
+use crate::error::{Error, Result};
+use crate::state::AppState;
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/:app_id/", get(auth_login))
+        .route("/redirect", get(auth_redirect))
+}
+
+/// Google's OIDC endpoints, overridable to sign in against a local mock server.
+struct GoogleConfig {
+    client_id: String,
+    client_secret: String,
+    issuer: String,
+    auth_url: String,
+    token_url: String,
+    jwks_url: String,
+}
+
+impl GoogleConfig {
+    /// The server's own Google client, failing on servers without one.
+    fn from_env() -> Result<Self> {
+        let client = |key: &str| env::var(key).map_err(|_| Error::AuthProviderUnconfigured);
+
+        Ok(Self::with_client(
+            client("GOOGLE_CLIENT_ID")?,
+            client("GOOGLE_CLIENT_SECRET")?,
+        ))
+    }
+
+    fn with_client(client_id: String, client_secret: String) -> Self {
+        let var = |key: &str, default: &str| env::var(key).unwrap_or(default.to_string());
+
+        Self {
+            client_id,
+            client_secret,
+            issuer: var("GOOGLE_ISSUER", "https://accounts.google.com"),
+            auth_url: var(
+                "GOOGLE_AUTH_URL",
+                "https://accounts.google.com/o/oauth2/v2/auth",
+            ),
+            token_url: var("GOOGLE_TOKEN_URL", "https://oauth2.googleapis.com/token"),
+            jwks_url: var(
+                "GOOGLE_JWKS_URL",
+                "https://www.googleapis.com/oauth2/v3/certs",
+            ),
+        }
+    }
+
+    /// The app's own Google client when it registered one, the server's otherwise.
+    async fn for_app(state: &AppState, app_id: &str) -> Result<Self> {
+        match app_credentials(state, app_id, "google").await? {
+            Some(credentials) => Ok(Self::with_client(
+                credentials.client_id.ok_or(Error::AuthInvalidParams)?,
+                credentials.client_secret,
+            )),
+            None => Self::from_env(),
+        }
+    }
+}
+
+fn redirect_uri() -> String {
+    format!("{}/api/auth/google/redirect", env::var("BASE_URL").unwrap())
+}
+
+#[derive(Deserialize)]
+struct AuthRequest {
+    code: String,
+}
+
+#[derive(Deserialize)]
+struct GoogleTokens {
+    id_token: String,
+}
+
+#[derive(Deserialize)]
+struct GoogleClaims {
+    sub: String,
+    nonce: Option<String>,
+    name: Option<String>,
+    picture: Option<String>,
+}
+
+struct Google;
+
+impl IdentityProvider for Google {
+    type Callback = AuthRequest;
+
+    fn name(&self) -> &str {
+        "google"
+    }
+
+    async fn authorize_url(&self, state: &AppState, app_id: &str, key: &str) -> Result<String> {
+        let config = GoogleConfig::for_app(state, app_id).await?;
+
+        let mut auth_query = Serializer::new(String::new());
+
+        auth_query.append_pair("response_type", "code");
+        auth_query.append_pair("client_id", &config.client_id);
+        auth_query.append_pair("redirect_uri", &redirect_uri());
+        auth_query.append_pair("scope", "openid profile");
+        auth_query.append_pair("state", key);
+        // the state doubles as the ID token nonce, tying the token to this login
+        auth_query.append_pair("nonce", key);
+
+        Ok(format!("{}?{}", config.auth_url, auth_query.finish()))
+    }
+
+    async fn identify(
+        &self,
+        state: &AppState,
+        app_id: &str,
+        callback: AuthRequest,
+        key: &str,
+    ) -> Result<Account> {
+        let config = GoogleConfig::for_app(state, app_id).await?;
+        let client = reqwest::Client::new();
+
+        let redirect_uri = redirect_uri();
+
+        let tokens: GoogleTokens = client
+            .post(&config.token_url)
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", &callback.code),
+                ("client_id", &config.client_id),
+                ("client_secret", &config.client_secret),
+                ("redirect_uri", &redirect_uri),
+            ])
+            .send()
+            .await
+            .map_err(|_| Error::AuthTokenExchangeFail)?
+            .json()
+            .await
+            .map_err(|_| Error::AuthTokenExchangeFail)?;
+
+        let claims: GoogleClaims = verify_id_token(
+            &client,
+            &config.jwks_url,
+            &config.issuer,
+            &config.client_id,
+            &tokens.id_token,
+        )
+        .await?;
+
+        if claims.nonce.as_deref() != Some(key) {
+            return Err(Error::JwtInvalidToken);
+        }
+
+        Ok(Account {
+            id: Some(claims.sub),
+            avatar: claims.picture,
+            username: claims.name,
+        })
+    }
+}
+
+async fn auth_login(
+    Path(app_id): Path<String>,
+    Query(query): Query<LoginQuery>,
+    State(mut state): State<AppState>,
+) -> Result<Redirect> {
+    begin_login(&mut state, &Google, app_id, query).await
+}
+
+async fn auth_redirect(
+    Query(callback): Query<Callback<AuthRequest>>,
+    State(mut state): State<AppState>,
+    cookies: Cookies,
+) -> Result<Redirect> {
+    finish_login(&mut state, &Google, &cookies, callback).await
+}
@@ -0,0 +1,236 @@
+use std::str::FromStr;
+
+use axum::response::Redirect;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use redis::Commands;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use sqlx::types::Uuid;
+use sqlx::PgPool;
+use tower_cookies::Cookies;
+
+use crate::api::auth::consent::complete_login;
+use crate::api::auth::device::device_callback_uri;
+use crate::api::auth::link::{check_link_binding, redeem_link_ticket};
+use crate::api::auth::{check_pkce, client_redirect, LoginState};
+use crate::db::app::{get_app, validate_redirect_uri};
+use crate::db::discord::get_discord_rules;
+use crate::db::provider::{get_app_credentials, AppCredentials};
+use crate::db::user::{create_user, get_identity_user, link_identity, update_identity, Account};
+use crate::error::{Error, Result};
+use crate::state::AppState;
+
+/// An upstream service users sign in with. Providers only talk to the upstream service,
+/// the login state, user bookkeeping and code issuing around it are shared.
+pub trait IdentityProvider {
+    /// The provider's own callback parameters, next to `state`.
+    type Callback: DeserializeOwned;
+
+    /// How long the user has to sign in at the provider, in seconds.
+    const STATE_TTL: i64 = 60 * 5;
+
+    /// Names the provider in login state keys.
+    fn name(&self) -> &str;
+
+    /// Where to send the user to sign in, handing `key` back as the callback's `state`.
+    async fn authorize_url(&self, state: &AppState, app_id: &str, key: &str) -> Result<String>;
+
+    /// Reads the signed in account off the provider's callback, for the app the login is for.
+    async fn identify(
+        &self,
+        state: &AppState,
+        app_id: &str,
+        callback: Self::Callback,
+        key: &str,
+    ) -> Result<Account>;
+
+    /// Finds the user the account is linked to, refreshing its username and avatar, or
+    /// creates a user for it. Returns the user's id.
+    async fn save_user(&self, pool: &PgPool, app_id: Uuid, account: &Account) -> Result<i32> {
+        let subject = account.id.as_deref().ok_or(Error::AuthUserParseFail)?;
+
+        match get_identity_user(pool, app_id, self.name(), subject).await? {
+            Some(user_id) => {
+                update_identity(pool, app_id, self.name(), account).await?;
+                Ok(user_id)
+            }
+            None => create_user(pool, app_id, self.name(), account).await,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct LoginQuery {
+    redirect_uri: String,
+    state: Option<String>,
+    scope: Option<String>,
+    nonce: Option<String>,
+    code_challenge: Option<String>,
+    code_challenge_method: Option<String>,
+    /// A ticket from the link endpoint, attaching the account to a signed in user.
+    link: Option<String>,
+}
+
+/// A provider callback, with the `state` every provider hands back.
+#[derive(Deserialize)]
+pub struct Callback<T> {
+    state: String,
+    #[serde(flatten)]
+    params: T,
+}
+
+/// The app's own credentials at the provider, when it registered its own application there.
+pub async fn app_credentials(
+    state: &AppState,
+    app_id: &str,
+    provider: &str,
+) -> Result<Option<AppCredentials>> {
+    let uuid = Uuid::from_str(app_id).map_err(|_| Error::UuidFail)?;
+
+    get_app_credentials(&state.pg, uuid, provider).await
+}
+
+fn state_key(provider: &impl IdentityProvider, key: &str) -> String {
+    format!("login:{}:{key}", provider.name())
+}
+
+/// Checks the client's login request and sends the user on to the provider.
+pub async fn begin_login<P: IdentityProvider>(
+    state: &mut AppState,
+    provider: &P,
+    app_id: String,
+    query: LoginQuery,
+) -> Result<Redirect> {
+    let uuid = Uuid::from_str(&app_id).map_err(|_| Error::UuidFail)?;
+
+    // the device verification page signs users in as the app itself
+    if query.redirect_uri != device_callback_uri(&app_id) {
+        validate_redirect_uri(&state.pg, uuid, &query.redirect_uri).await?;
+    }
+
+    let app = get_app(&state.pg, uuid).await?;
+
+    if !app
+        .providers
+        .iter()
+        .any(|enabled| enabled == provider.name())
+    {
+        return Err(Error::AuthInvalidParams);
+    }
+
+    check_pkce(
+        &state.pg,
+        uuid,
+        &query.code_challenge,
+        &query.code_challenge_method,
+    )
+    .await?;
+
+    let link = match &query.link {
+        Some(ticket) => Some(redeem_link_ticket(state, &app_id, ticket)?),
+        None => None,
+    };
+
+    let login_state = LoginState {
+        app_id,
+        redirect_uri: query.redirect_uri,
+        client_state: query.state,
+        code_challenge: query.code_challenge,
+        scope: query.scope,
+        nonce: query.nonce,
+        link,
+    };
+
+    let key: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect();
+
+    let auth_url = provider
+        .authorize_url(state, &login_state.app_id, &key)
+        .await?;
+
+    state
+        .redis
+        .set::<_, _, ()>(
+            state_key(provider, &key),
+            serde_json::to_string(&login_state).map_err(|_| Error::RedisSetFail)?,
+        )
+        .map_err(|_| Error::RedisSetFail)?;
+
+    state
+        .redis
+        .expire::<_, ()>(state_key(provider, &key), P::STATE_TTL)
+        .map_err(|_| Error::RedisExpireFail)?;
+
+    Ok(Redirect::to(&auth_url))
+}
+
+/// Picks the login back up after the provider, saving the user and finishing the login.
+pub async fn finish_login<P: IdentityProvider>(
+    state: &mut AppState,
+    provider: &P,
+    cookies: &Cookies,
+    callback: Callback<P::Callback>,
+) -> Result<Redirect> {
+    let key = state_key(provider, &callback.state);
+
+    let login_state: Option<String> = state.redis.get(&key).map_err(|_| Error::RedisGetFail)?;
+    let login_state = login_state.ok_or(Error::RedisGetEmpty)?;
+
+    state
+        .redis
+        .del::<_, ()>(&key)
+        .map_err(|_| Error::RedisDelFail)?;
+
+    let login_state: LoginState =
+        serde_json::from_str(&login_state).map_err(|_| Error::RedisGetFail)?;
+
+    let uuid = Uuid::from_str(&login_state.app_id).map_err(|_| Error::UuidFail)?;
+
+    // guild rules can only be checked on discord logins, so apps with any take no others,
+    // to sign in with or to link
+    let account =
+        if provider.name() != "discord" && !get_discord_rules(&state.pg, uuid).await?.is_empty() {
+            Err(Error::OAuthAccessDenied)
+        } else {
+            provider
+                .identify(state, &login_state.app_id, callback.params, &callback.state)
+                .await
+        };
+
+    // providers refuse accounts the app doesn't admit, which the client hears about
+    let account = match account {
+        Err(Error::OAuthAccessDenied) => {
+            return Ok(Redirect::to(&client_redirect(
+                &login_state.redirect_uri,
+                &[("error", "access_denied")],
+                login_state.client_state.as_deref(),
+            )));
+        }
+        account => account?,
+    };
+
+    let user_id = match &login_state.link {
+        Some(link) => {
+            check_link_binding(cookies, link)?;
+
+            let user_id = link.user_id;
+
+            if !link_identity(&state.pg, uuid, user_id, provider.name(), &account).await? {
+                return Ok(Redirect::to(&client_redirect(
+                    &login_state.redirect_uri,
+                    &[("error", "identity_conflict")],
+                    login_state.client_state.as_deref(),
+                )));
+            }
+
+            user_id
+        }
+        None => provider.save_user(&state.pg, uuid, &account).await?,
+    };
+
+    complete_login(state, user_id, login_state).await
+}
@@ -0,0 +1,216 @@
+use std::env;
+use std::str::FromStr;
+
+use axum::extract::{Path, State};
+use axum::routing::post;
+use axum::{Json, Router};
+use http::HeaderMap;
+use oauth2::url::form_urlencoded::Serializer;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use redis::Commands;
+use serde::{Deserialize, Serialize};
+use sqlx::types::Uuid;
+use tower_cookies::cookie::SameSite;
+use tower_cookies::{Cookie, Cookies};
+
+use crate::api::auth::upstream::login_path;
+use crate::api::auth::{bearer_token, verify_access_token};
+use crate::db::user::unlink_identity;
+use crate::error::{Error, Result};
+use crate::state::AppState;
+
+/// How long the client has to send the user to the provider after asking to link.
+const LINK_TICKET_TTL: i64 = 60;
+
+/// The cookie tying a link ticket to the browser that asked for it.
+const LINK_COOKIE: &str = "link";
+
+/// What a link ticket is stored as until the login redeems it.
+#[derive(Serialize, Deserialize)]
+pub struct LinkTicket {
+    pub user_id: i32,
+    /// The `link` cookie value the login has to finish with.
+    pub binding: String,
+}
+
+fn random_string() -> String {
+    thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect()
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/:app_id/link", post(link))
+        .route("/:app_id/unlink", post(unlink))
+}
+
+/// The signed in user, from a bearer access token or the `access` cookie.
+async fn current_user(
+    state: &mut AppState,
+    app_id: &str,
+    cookies: &Cookies,
+    headers: &HeaderMap,
+) -> Result<i32> {
+    let token = match bearer_token(headers) {
+        Some(token) => token.to_string(),
+        None => cookies
+            .get("access")
+            .ok_or(Error::AuthMissingCookie)?
+            .value()
+            .to_string(),
+    };
+
+    let claims = verify_access_token(state, app_id, &token).await?;
+
+    claims
+        .user
+        .map(|user| user.user_id)
+        .ok_or(Error::JwtInvalidToken)
+}
+
+#[derive(Deserialize)]
+struct LinkRequest {
+    provider: String,
+    redirect_uri: String,
+    state: Option<String>,
+    scope: Option<String>,
+    code_challenge: Option<String>,
+    code_challenge_method: Option<String>,
+}
+
+#[derive(Serialize)]
+struct LinkResponse {
+    url: String,
+}
+
+/// Starts linking another provider to the signed in user. Returns the provider login to
+/// send the user to, which ends like a normal login, with the one-time code or an
+/// `identity_conflict` error when the account belongs to another user. The login has to
+/// finish in the same browser, which gets the ticket's `link` cookie.
+async fn link(
+    Path(app_id): Path<String>,
+    State(mut state): State<AppState>,
+    cookies: Cookies,
+    headers: HeaderMap,
+    Json(body): Json<LinkRequest>,
+) -> Result<Json<LinkResponse>> {
+    let user_id = current_user(&mut state, &app_id, &cookies, &headers).await?;
+
+    let login_path = login_path(&state.pg, &body.provider, &app_id)
+        .await?
+        .ok_or(Error::AuthInvalidParams)?;
+
+    let ticket = random_string();
+    let binding = random_string();
+
+    let ticket_key = format!("{app_id}:link:{ticket}");
+
+    let link_ticket = LinkTicket {
+        user_id,
+        binding: binding.clone(),
+    };
+
+    state
+        .redis
+        .set::<_, _, ()>(
+            &ticket_key,
+            serde_json::to_string(&link_ticket).map_err(|_| Error::RedisSetFail)?,
+        )
+        .map_err(|_| Error::RedisSetFail)?;
+
+    state
+        .redis
+        .expire::<_, ()>(&ticket_key, LINK_TICKET_TTL)
+        .map_err(|_| Error::RedisExpireFail)?;
+
+    let mut link_cookie = Cookie::build(LINK_COOKIE, binding)
+        .path("/api/auth")
+        .same_site(SameSite::Lax)
+        .http_only(true);
+
+    if env::var("DEV").is_err() {
+        link_cookie = link_cookie.secure(true);
+    }
+
+    cookies.add(link_cookie.finish());
+
+    let mut login = Serializer::new(String::new());
+
+    login.append_pair("redirect_uri", &body.redirect_uri);
+    login.append_pair("link", &ticket);
+
+    let optional = [
+        ("state", &body.state),
+        ("scope", &body.scope),
+        ("code_challenge", &body.code_challenge),
+        ("code_challenge_method", &body.code_challenge_method),
+    ];
+
+    for (key, value) in optional {
+        if let Some(value) = value {
+            login.append_pair(key, value);
+        }
+    }
+
+    Ok(Json(LinkResponse {
+        url: format!("{login_path}?{}", login.finish()),
+    }))
+}
+
+/// Trades a link ticket for the user it was issued to.
+pub fn redeem_link_ticket(state: &mut AppState, app_id: &str, ticket: &str) -> Result<LinkTicket> {
+    let ticket_key = format!("{app_id}:link:{ticket}");
+
+    let link_ticket: Option<String> = state
+        .redis
+        .get(&ticket_key)
+        .map_err(|_| Error::RedisGetFail)?;
+
+    state
+        .redis
+        .del::<_, ()>(&ticket_key)
+        .map_err(|_| Error::RedisDelFail)?;
+
+    let link_ticket = link_ticket.ok_or(Error::RedisGetEmpty)?;
+
+    serde_json::from_str(&link_ticket).map_err(|_| Error::RedisGetFail)
+}
+
+/// Checks a link is finishing in the browser that asked for it, so a leaked ticket or
+/// provider login can't attach an account to someone else's user.
+pub fn check_link_binding(cookies: &Cookies, link_ticket: &LinkTicket) -> Result<()> {
+    let link_cookie = cookies.get(LINK_COOKIE).ok_or(Error::AuthMissingCookie)?;
+
+    if link_cookie.value() != link_ticket.binding {
+        return Err(Error::AuthMissingCookie);
+    }
+
+    cookies.remove(Cookie::build(LINK_COOKIE, "").path("/api/auth").finish());
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct UnlinkRequest {
+    provider: String,
+    subject: String,
+}
+
+/// Unlinks a provider account from the signed in user, as long as another one remains.
+async fn unlink(
+    Path(app_id): Path<String>,
+    State(mut state): State<AppState>,
+    cookies: Cookies,
+    headers: HeaderMap,
+    Json(body): Json<UnlinkRequest>,
+) -> Result<()> {
+    let user_id = current_user(&mut state, &app_id, &cookies, &headers).await?;
+
+    let uuid = Uuid::from_str(&app_id).map_err(|_| Error::UuidFail)?;
+
+    unlink_identity(&state.pg, uuid, user_id, &body.provider, &body.subject).await
+}
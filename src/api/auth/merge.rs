@@ -0,0 +1,90 @@
+use std::str::FromStr;
+
+use axum::extract::{Path, Query, State};
+use axum::routing::get;
+use axum::{Json, Router};
+use http::HeaderMap;
+use redis::Commands;
+use serde::Deserialize;
+use sqlx::types::Uuid;
+use tower_cookies::cookie::time::format_description;
+use tower_cookies::cookie::time::format_description::well_known::Rfc3339;
+use tower_cookies::cookie::time::OffsetDateTime;
+
+use crate::api::auth::oauth::authenticate_client;
+use crate::db::user::{get_merges, merge_users, UserMerge};
+use crate::error::{Error, Result};
+use crate::state::AppState;
+
+pub fn routes() -> Router<AppState> {
+    Router::new().route("/:app_id/merges", get(merges))
+}
+
+/// Merges the duplicate user into the primary one and signs the duplicate out everywhere,
+/// their refresh tokens being tied to an id that no longer exists.
+pub async fn merge_accounts(
+    state: &mut AppState,
+    app_id: &str,
+    primary: i32,
+    duplicate: i32,
+) -> Result<()> {
+    let uuid = Uuid::from_str(app_id).map_err(|_| Error::UuidFail)?;
+
+    merge_users(&state.pg, uuid, primary, duplicate).await?;
+
+    state
+        .redis
+        .del::<_, ()>(format!("{app_id}:{duplicate}"))
+        .map_err(|_| Error::RedisDelFail)?;
+
+    println!("MERGE - user {duplicate} of app {app_id} merged into {primary}");
+
+    Ok(())
+}
+
+/// Whether `since` is a timestamp, either as `merged_at` is given out or in RFC 3339.
+fn valid_since(since: &str) -> bool {
+    let merged_at = format_description::parse_owned::<2>(
+        "[year]-[month]-[day] [hour]:[minute]:[second][optional [.[subsecond]]]\
+         [offset_hour sign:mandatory][optional [:[offset_minute]]]",
+    )
+    .unwrap();
+
+    OffsetDateTime::parse(since, &merged_at).is_ok()
+        || OffsetDateTime::parse(since, &Rfc3339).is_ok()
+}
+
+#[derive(Deserialize)]
+struct MergesQuery {
+    since: Option<String>,
+}
+
+/// Lets a confidential client catch up on merged users, to move data kept under an old
+/// `user_id` to the one it was merged into. Pass the last seen `merged_at` as `since`.
+/// The client authenticates with HTTP Basic, keeping its secret out of the url.
+async fn merges(
+    Path(app_id): Path<String>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<MergesQuery>,
+) -> Result<Json<Vec<UserMerge>>> {
+    let authenticated = authenticate_client(&state.pg, &app_id, &headers, None, None).await?;
+
+    if !authenticated {
+        return Err(Error::OAuthInvalidClient);
+    }
+
+    if query
+        .since
+        .as_deref()
+        .is_some_and(|since| !valid_since(since))
+    {
+        return Err(Error::OAuthInvalidRequest);
+    }
+
+    let uuid = Uuid::from_str(&app_id).map_err(|_| Error::UuidFail)?;
+
+    Ok(Json(
+        get_merges(&state.pg, uuid, query.since.as_deref()).await?,
+    ))
+}
@@ -1,20 +1,48 @@
+pub mod chooser;
+pub mod consent;
+pub mod device;
 pub mod discord;
+pub mod github;
+pub mod google;
+pub mod identity;
+pub mod link;
+pub mod merge;
+pub mod oauth;
+pub mod oidc;
+pub mod profiles;
 pub mod steam;
+pub mod templates;
+pub mod upstream;
+
+#[cfg(test)]
+mod stand_in;
 
 use std::env;
 use std::str::FromStr;
 
-use crate::db::app::{get_private_key, get_public_key};
-use crate::db::user::get_user;
+use crate::api::auth::link::LinkTicket;
+use crate::api::auth::oauth::authenticate_client;
+use crate::db::app::{get_app, get_session_policy, SessionPolicy, TokenDelivery};
+use crate::db::key::{get_private_key, get_public_keys};
+use crate::db::user::{get_user_by_id, User};
 use crate::error::{Error, Result};
-use crate::jwt::{gen_access_token, gen_refresh_token, verify_token};
+use crate::jwt::{
+    gen_access_token, gen_jti, gen_refresh_token, now, verify_claims, Claims, TokenUse,
+};
 use crate::state::AppState;
 use axum::extract::State;
+use axum::response::{IntoResponse, Response};
 use axum::{extract::Path, routing::post};
 use axum::{Json, Router};
-use redis::Commands;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use http::header::{AUTHORIZATION, CACHE_CONTROL};
+use http::HeaderMap;
+use oauth2::url::form_urlencoded::Serializer;
+use openssl::sha::sha256;
+use redis::{Commands, Script};
 use serde::{Deserialize, Serialize};
-use sqlx::types::Uuid;
+use sqlx::{types::Uuid, PgPool};
+use tokio::time::sleep;
 use tower_cookies::cookie::time::Duration;
 use tower_cookies::cookie::SameSite;
 use tower_cookies::{Cookie, Cookies};
@@ -23,143 +51,750 @@ pub fn routes() -> Router<AppState> {
     Router::new()
         .nest("/discord", discord::routes())
         .nest("/steam", steam::routes())
+        .nest("/google", google::routes())
+        .nest("/github", github::routes())
+        .nest("/provider", upstream::routes())
+        .merge(chooser::routes())
+        .merge(oauth::routes())
+        .merge(device::routes())
+        .merge(consent::routes())
+        .merge(link::routes())
+        .merge(merge::routes())
+        .merge(oidc::routes())
         .route("/:app_id/token", post(gen_tokens))
         .route("/:app_id/token/refresh", post(refresh_tokens))
         .route("/:app_id/logout", post(logout))
 }
 
+/// Tokens as returned to clients using body delivery.
 #[derive(Debug, Serialize)]
 struct Token {
     access_token: String,
+    refresh_token: String,
+    expires_in: usize,
+    token_type: &'static str,
+}
+
+/// Login parameters carried through the provider round trip.
+#[derive(Serialize, Deserialize)]
+pub struct LoginState {
+    pub app_id: String,
+    pub redirect_uri: String,
+    pub client_state: Option<String>,
+    pub code_challenge: Option<String>,
+    pub scope: Option<String>,
+    pub nonce: Option<String>,
+    /// The signed in user the provider account is being linked to.
+    #[serde(default)]
+    pub link: Option<LinkTicket>,
+}
+
+/// What a one-time login code is stored as until it is redeemed.
+#[derive(Serialize, Deserialize)]
+pub struct AuthCode {
+    pub user_id: i32,
+    pub redirect_uri: String,
+    pub code_challenge: Option<String>,
+    pub scope: Option<String>,
+    pub nonce: Option<String>,
+    pub auth_time: usize,
+}
+
+impl AuthCode {
+    /// Starts a code for the user that just signed in with the given login.
+    pub fn new(user_id: i32, login: LoginState) -> Result<Self> {
+        Ok(Self {
+            user_id,
+            redirect_uri: login.redirect_uri,
+            code_challenge: login.code_challenge,
+            scope: login.scope,
+            nonce: login.nonce,
+            auth_time: now()?,
+        })
+    }
+
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scope
+            .as_ref()
+            .is_some_and(|scopes| scopes.split(' ').any(|s| s == scope))
+    }
+}
+
+/// Reads the token from an `Authorization: Bearer` header.
+pub fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+/// Builds the redirect back to the client with the given parameters and the client's `state`.
+pub fn client_redirect(
+    redirect_uri: &str,
+    params: &[(&str, &str)],
+    client_state: Option<&str>,
+) -> String {
+    let mut query = Serializer::new(String::new());
+
+    query.extend_pairs(params);
+
+    if let Some(client_state) = client_state {
+        query.append_pair("state", client_state);
+    }
+
+    let separator = if redirect_uri.contains('?') { '&' } else { '?' };
+
+    format!("{redirect_uri}{separator}{}", query.finish())
+}
+
+/// Rejects unsupported challenge methods and missing challenges for apps requiring PKCE.
+pub async fn check_pkce(
+    pool: &PgPool,
+    app_id: Uuid,
+    code_challenge: &Option<String>,
+    code_challenge_method: &Option<String>,
+) -> Result<()> {
+    // RFC 7636 defaults to the unsupported plain method, so S256 has to be named
+    if code_challenge.is_some() && code_challenge_method.is_none() {
+        return Err(Error::OAuthInvalidRequest);
+    }
+
+    if code_challenge_method
+        .as_ref()
+        .is_some_and(|method| method != "S256")
+    {
+        return Err(Error::AuthInvalidParams);
+    }
+
+    if code_challenge.is_none() && get_app(pool, app_id).await?.require_pkce {
+        return Err(Error::AuthPkceRequired);
+    }
+
+    Ok(())
+}
+
+/// Consumes a one-time login code, checking the PKCE verifier when a challenge was sent
+/// and the redirect uri when the client repeats it.
+pub async fn redeem_code(
+    state: &mut AppState,
+    app_id: &str,
+    code: &str,
+    redirect_uri: Option<&str>,
+    code_verifier: Option<&str>,
+) -> Result<(User, AuthCode)> {
+    let key = format!("{app_id}:code:{code}");
+
+    let auth_code: Option<String> = state.redis.get(&key).map_err(|_| Error::RedisGetFail)?;
+    let auth_code: AuthCode = serde_json::from_str(&auth_code.ok_or(Error::RedisGetEmpty)?)
+        .map_err(|_| Error::RedisGetFail)?;
+
+    state
+        .redis
+        .del::<_, ()>(&key)
+        .map_err(|_| Error::RedisDelFail)?;
+
+    if redirect_uri.is_some_and(|uri| uri != auth_code.redirect_uri) {
+        return Err(Error::AuthRedirectMismatch);
+    }
+
+    let uuid = Uuid::from_str(app_id).map_err(|_| Error::UuidFail)?;
+
+    match &auth_code.code_challenge {
+        Some(code_challenge) => {
+            let code_verifier = code_verifier.ok_or(Error::AuthPkceRequired)?;
+
+            if !(43..=128).contains(&code_verifier.len())
+                || &URL_SAFE_NO_PAD.encode(sha256(code_verifier.as_bytes())) != code_challenge
+            {
+                return Err(Error::AuthPkceMismatch);
+            }
+        }
+        None => {
+            if get_app(&state.pg, uuid).await?.require_pkce {
+                return Err(Error::AuthPkceRequired);
+            }
+        }
+    }
+
+    let user = get_user_by_id(&state.pg, uuid, auth_code.user_id)
+        .await?
+        .ok_or(Error::PgNone)?;
+
+    Ok((user, auth_code))
 }
 
 #[derive(Debug, Deserialize)]
 struct TokenRequest {
     code: String,
+    code_verifier: Option<String>,
+    delivery: Option<TokenDelivery>,
+    client_id: Option<String>,
+    client_secret: Option<String>,
 }
 
 async fn gen_tokens(
     cookies: Cookies,
     Path(app_id): Path<String>,
     State(mut state): State<AppState>,
+    headers: HeaderMap,
     Json(query): Json<TokenRequest>,
-) -> Result<()> {
-    let key = format!("{}:code:{}", app_id, query.code);
+) -> Result<Response> {
+    // confidential apps authenticate here just like at the oauth token endpoint
+    authenticate_client(
+        &state.pg,
+        &app_id,
+        &headers,
+        query.client_id.as_deref(),
+        query.client_secret.as_deref(),
+    )
+    .await?;
+
+    let (user, auth_code) = redeem_code(
+        &mut state,
+        &app_id,
+        &query.code,
+        None,
+        query.code_verifier.as_deref(),
+    )
+    .await?;
+
+    let tokens = issue_tokens(&mut state, &app_id, &user, auth_code.scope.as_deref()).await?;
+
+    let delivery = match query.delivery {
+        Some(delivery) => delivery,
+        None => app_delivery(&state, &app_id).await?,
+    };
+
+    Ok(deliver_tokens(&cookies, tokens, delivery))
+}
 
-    let user_id: Option<String> = state.redis.get(&key).map_err(|_| Error::RedisGetFail)?;
-    let user_id = user_id.ok_or(Error::RedisGetEmpty)?;
+/// A refresh token sent by a client that can't use cookies.
+#[derive(Debug, Deserialize)]
+struct RefreshRequest {
+    refresh_token: Option<String>,
+    delivery: Option<TokenDelivery>,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+}
 
-    state.redis.del(&key).map_err(|_| Error::RedisDelFail)?;
+async fn refresh_tokens(
+    cookies: Cookies,
+    Path(app_id): Path<String>,
+    State(mut state): State<AppState>,
+    headers: HeaderMap,
+    body: Option<Json<RefreshRequest>>,
+) -> Result<Response> {
+    let body = body.map(|Json(body)| body);
+    let delivery = body.as_ref().and_then(|body| body.delivery);
+
+    // a bearer refresh token takes the authorization header, so secrets can come in the body
+    authenticate_client(
+        &state.pg,
+        &app_id,
+        &headers,
+        body.as_ref().and_then(|body| body.client_id.as_deref()),
+        body.as_ref().and_then(|body| body.client_secret.as_deref()),
+    )
+    .await?;
+
+    let (refresh_token, from_cookie) = refresh_token_from(&cookies, &headers, body)?;
+
+    let tokens = rotate_tokens(&mut state, &app_id, &refresh_token).await?;
+
+    // a token that didn't come from a cookie is answered the same way unless asked otherwise
+    let delivery = match (delivery, from_cookie) {
+        (Some(delivery), _) => delivery,
+        (None, false) => TokenDelivery::Body,
+        (None, true) => app_delivery(&state, &app_id).await?,
+    };
+
+    Ok(deliver_tokens(&cookies, tokens, delivery))
+}
 
-    let uuid = Uuid::from_str(&app_id).map_err(|_| Error::UuidFail)?;
+/// Finds the refresh token in the request body, the `refresh` cookie or an
+/// `Authorization: Bearer` header, in that order, and tells whether it was the cookie.
+/// Browsers may send their access token as the header, so the cookie comes first.
+fn refresh_token_from(
+    cookies: &Cookies,
+    headers: &HeaderMap,
+    body: Option<RefreshRequest>,
+) -> Result<(String, bool)> {
+    if let Some(refresh_token) = body.and_then(|body| body.refresh_token) {
+        return Ok((refresh_token, false));
+    }
 
-    let user = get_user(&state.pg, uuid, &user_id)
-        .await?
-        .ok_or(Error::PgNone)?;
+    if let Some(refresh_cookie) = cookies.get("refresh") {
+        return Ok((refresh_cookie.value().to_string(), true));
+    }
 
-    let private_key = get_private_key(&state.pg, uuid).await?;
+    let refresh_token = bearer_token(headers).ok_or(Error::AuthMissingCookie)?;
+
+    Ok((refresh_token.to_string(), false))
+}
+
+async fn app_delivery(state: &AppState, app_id: &str) -> Result<TokenDelivery> {
+    let uuid = Uuid::from_str(app_id).map_err(|_| Error::UuidFail)?;
+
+    Ok(get_app(&state.pg, uuid).await?.token_delivery)
+}
+
+fn deliver_tokens(cookies: &Cookies, tokens: Tokens, delivery: TokenDelivery) -> Response {
+    match delivery {
+        TokenDelivery::Cookie => {
+            set_token_cookies(cookies, &tokens, false);
+
+            ().into_response()
+        }
+        TokenDelivery::Body => (
+            [(CACHE_CONTROL, "no-store")],
+            Json(Token {
+                access_token: tokens.access_token,
+                refresh_token: tokens.refresh_token,
+                expires_in: tokens.access_expires_in,
+                token_type: "Bearer",
+            }),
+        )
+            .into_response(),
+    }
+}
+
+/// A freshly minted token pair with the lifetimes the app's session policy gave it.
+#[derive(Serialize, Deserialize)]
+pub struct Tokens {
+    pub access_token: String,
+    pub refresh_token: String,
+    pub access_expires_in: usize,
+    pub refresh_expires_in: usize,
+    pub scope: Option<String>,
+}
+
+/// Starts a new session, minting an access/refresh pair for the granted `scope` under the
+/// app's session policy.
+pub async fn issue_tokens(
+    state: &mut AppState,
+    app_id: &str,
+    user: &User,
+    scope: Option<&str>,
+) -> Result<Tokens> {
+    let uuid = Uuid::from_str(app_id).map_err(|_| Error::UuidFail)?;
+    let policy = get_session_policy(&state.pg, uuid).await?;
+
+    mint_tokens(state, app_id, user, scope, &gen_jti(), now()?, &policy).await
+}
+
+/// Atomically takes a refresh token out of the session set, marking it rotated and opening
+/// its grace window. A token that was already rotated instead yields its successor while the
+/// window is open (empty until the successor is minted), else the family it belonged to.
+const ROTATE_SCRIPT: &str = r"
+    local issued_at = redis.call('ZSCORE', KEYS[1], ARGV[1])
+
+    if issued_at then
+        redis.call('ZREM', KEYS[1], ARGV[1])
+        redis.call('SET', KEYS[2], ARGV[2], 'EX', ARGV[3])
 
-    let access_token = gen_access_token(&user, &app_id, private_key.as_bytes())?;
-    let refresh_token = gen_refresh_token(&user, &app_id, private_key.as_bytes())?;
+        if tonumber(ARGV[4]) > 0 then
+            redis.call('SET', KEYS[3], '', 'EX', ARGV[4])
+        end
 
+        return {'rotated', issued_at}
+    end
+
+    local successor = redis.call('GET', KEYS[3])
+
+    if successor then
+        return {'grace', successor}
+    end
+
+    local family = redis.call('GET', KEYS[2])
+
+    if family then
+        return {'reuse', family}
+    end
+
+    return {'missing', ''}
+";
+
+/// Swaps a refresh token that is still in the session set for a new pair, keeping the
+/// session's original `auth_time` so refreshing can't outlive the session lifetime.
+/// Within the app's grace window a just-rotated token returns the same successor pair, so
+/// tabs refreshing at once don't log each other out. Presenting it after that revokes its
+/// whole family, since either the client or whoever stole the token holds a stale copy.
+pub async fn rotate_tokens(
+    state: &mut AppState,
+    app_id: &str,
+    refresh_token: &str,
+) -> Result<Tokens> {
+    let uuid = Uuid::from_str(app_id).map_err(|_| Error::UuidFail)?;
+    let public_keys = get_public_keys(&state.pg, uuid).await?;
+
+    let claims = verify_claims(refresh_token, app_id, &public_keys)?;
+
+    // tokens from before `token_use` and `jti` are left to the session set to tell apart
+    if claims.token_use != TokenUse::Refresh && !claims.jti.is_empty() {
+        return Err(Error::JwtInvalidToken);
+    }
+
+    let user = claims.user.ok_or(Error::JwtInvalidToken)?;
     let user_id = user.user_id;
 
-    let token_key = format!("{app_id}:{user_id}");
+    let policy = get_session_policy(&state.pg, uuid).await?;
+
+    // tokens minted before families existed start a new one
+    let family = claims.family.unwrap_or_else(gen_jti);
+
+    // and, lacking a `jti`, are told apart by the token itself
+    let token_id = match claims.jti.as_str() {
+        "" => refresh_token,
+        jti => jti,
+    };
+
+    let rotated_key = format!("{app_id}:rotated:{token_id}");
+    let grace_key = format!("{app_id}:grace:{token_id}");
+
+    let (outcome, value): (String, String) = Script::new(ROTATE_SCRIPT)
+        .key(format!("{app_id}:{user_id}"))
+        .key(&rotated_key)
+        .key(&grace_key)
+        .arg(refresh_token)
+        .arg(&family)
+        .arg(claims.exp.saturating_sub(now()?).max(1))
+        .arg(policy.refresh_grace)
+        .invoke(&mut state.redis)
+        .map_err(|_| Error::RedisGetFail)?;
 
-    state
-        .redis
-        .zadd(&token_key, &refresh_token, 1)
-        .map_err(|_| Error::RedisSetFail)?;
+    match outcome.as_str() {
+        "rotated" => {}
+        "grace" => return await_successor(state, &grace_key, value).await,
+        "reuse" => {
+            revoke_family(state, app_id, user_id, &value)?;
 
-    state
-        .redis
-        .expire(&refresh_token, 60 * 60 * 24 * 3)
-        .map_err(|_| Error::RedisExpireFail)?;
+            println!(
+                "SECURITY - refresh token reuse in app {app_id}, \
+                revoked family {value} of user {user_id}"
+            );
 
-    let mut refresh_cookie = Cookie::build("refresh", refresh_token)
-        .path("/")
-        .same_site(SameSite::Lax)
-        .max_age(Duration::seconds(60 * 60 * 24 * 3))
-        .http_only(true);
+            return Err(Error::AuthRefreshReuse);
+        }
+        _ => return Err(Error::RedisGetEmpty),
+    }
 
-    let mut access_cookie = Cookie::build("access", access_token)
-        .path("/")
-        .same_site(SameSite::Lax)
-        .max_age(Duration::seconds(60 * 5))
-        .http_only(false);
+    let issued_at = value.parse::<f64>().map_err(|_| Error::RedisGetFail)? as usize;
 
-    if env::var("DEV").is_err() {
-        let domain = env::var("BASE_DOMAIN").unwrap();
+    // tokens from before the score was the issue time were added with a score of 1
+    let legacy = issued_at <= 1;
 
-        refresh_cookie = refresh_cookie.domain(domain.clone()).secure(true);
+    if let Some(idle_timeout) = policy.idle_timeout.filter(|_| !legacy) {
+        if now()?.saturating_sub(issued_at) > idle_timeout as usize {
+            state
+                .redis
+                .del::<_, ()>(&grace_key)
+                .map_err(|_| Error::RedisDelFail)?;
 
-        access_cookie = access_cookie.domain(domain).secure(true);
+            return Err(Error::JwtInvalidToken);
+        }
     }
 
-    let refresh_cookie = refresh_cookie.finish();
-    let access_cookie = access_cookie.finish();
+    // tokens minted before `auth_time` was tracked start their session at issuance, and
+    // those from before `iat` at this refresh
+    let auth_time = match (claims.auth_time, claims.iat) {
+        (0, 0) => now()?,
+        (0, iat) => iat,
+        (auth_time, _) => auth_time,
+    };
+
+    let tokens = mint_tokens(
+        state,
+        app_id,
+        &user,
+        claims.scope.as_deref(),
+        &family,
+        auth_time,
+        &policy,
+    )
+    .await?;
+
+    if policy.refresh_grace > 0 {
+        let successor = serde_json::to_string(&tokens).map_err(|_| Error::RedisSetFail)?;
+
+        state
+            .redis
+            .set::<_, _, ()>(&grace_key, successor)
+            .map_err(|_| Error::RedisSetFail)?;
+
+        state
+            .redis
+            .expire::<_, ()>(&grace_key, policy.refresh_grace as i64)
+            .map_err(|_| Error::RedisExpireFail)?;
+
+        // remembered with the family, so ending it closes the window too
+        let family_grace_key = format!("{app_id}:family:{family}:grace");
+
+        state
+            .redis
+            .set::<_, _, ()>(&family_grace_key, &grace_key)
+            .map_err(|_| Error::RedisSetFail)?;
+
+        state
+            .redis
+            .expire::<_, ()>(&family_grace_key, policy.refresh_grace as i64)
+            .map_err(|_| Error::RedisExpireFail)?;
+    }
 
-    cookies.add(refresh_cookie);
-    cookies.add(access_cookie);
+    Ok(tokens)
+}
+
+/// Waits for the concurrent rotation that won the race to store its successor pair.
+async fn await_successor(
+    state: &mut AppState,
+    grace_key: &str,
+    successor: String,
+) -> Result<Tokens> {
+    let mut successor = Some(successor);
+
+    for _ in 0..20 {
+        match successor.as_deref() {
+            None => break,
+            Some("") => {}
+            Some(tokens) => return serde_json::from_str(tokens).map_err(|_| Error::RedisGetFail),
+        }
+
+        sleep(std::time::Duration::from_millis(50)).await;
+
+        successor = state
+            .redis
+            .get(grace_key)
+            .map_err(|_| Error::RedisGetFail)?;
+    }
+
+    Err(Error::RedisGetEmpty)
+}
+
+/// Ends a token family by dropping its current refresh token from the user's session set,
+/// and closing the grace window that would still hand that token out.
+fn revoke_family(state: &mut AppState, app_id: &str, user_id: i32, family: &str) -> Result<()> {
+    let family_key = format!("{app_id}:family:{family}");
+    let family_grace_key = format!("{app_id}:family:{family}:grace");
+
+    let grace_key: Option<String> = state
+        .redis
+        .get(&family_grace_key)
+        .map_err(|_| Error::RedisGetFail)?;
+
+    if let Some(grace_key) = grace_key {
+        state
+            .redis
+            .del::<_, ()>(grace_key)
+            .map_err(|_| Error::RedisDelFail)?;
+    }
+
+    state
+        .redis
+        .del::<_, ()>(&family_grace_key)
+        .map_err(|_| Error::RedisDelFail)?;
+
+    let current: Option<String> = state
+        .redis
+        .get(&family_key)
+        .map_err(|_| Error::RedisGetFail)?;
+
+    if let Some(current) = current {
+        state
+            .redis
+            .zrem::<_, _, ()>(format!("{app_id}:{user_id}"), current)
+            .map_err(|_| Error::RedisDelFail)?;
+    }
+
+    state
+        .redis
+        .del::<_, ()>(&family_key)
+        .map_err(|_| Error::RedisDelFail)?;
 
     Ok(())
 }
 
-async fn refresh_tokens(
-    cookies: Cookies,
-    Path(app_id): Path<String>,
-    State(mut state): State<AppState>,
-) -> Result<()> {
-    let refresh_cookie = &cookies.get("refresh").ok_or(Error::AuthMissingCookie)?;
+/// Mints a pair carrying the parts of the user `scope` grants, for a session that started at
+/// `auth_time`, and registers the refresh token
+/// in the user's session set, scored by when it was issued, and as the head of its family.
+async fn mint_tokens(
+    state: &mut AppState,
+    app_id: &str,
+    user: &User,
+    scope: Option<&str>,
+    family: &str,
+    auth_time: usize,
+    policy: &SessionPolicy,
+) -> Result<Tokens> {
+    let uuid = Uuid::from_str(app_id).map_err(|_| Error::UuidFail)?;
+
+    let now = now()?;
+
+    let refresh_exp = match policy.session_ttl {
+        Some(session_ttl) => {
+            (now + policy.refresh_ttl as usize).min(auth_time + session_ttl as usize)
+        }
+        None => now + policy.refresh_ttl as usize,
+    };
+
+    if refresh_exp <= now {
+        return Err(Error::JwtInvalidToken);
+    }
 
-    let refresh_token = refresh_cookie.value();
+    let refresh_expires_in = refresh_exp - now;
+    let access_expires_in = (policy.access_ttl as usize).min(refresh_expires_in);
 
-    let uuid = Uuid::from_str(&app_id).map_err(|_| Error::UuidFail)?;
-    let public_key = get_public_key(&state.pg, uuid).await?;
+    let private_key = get_private_key(&state.pg, uuid).await?;
+
+    let app_id = app_id.to_string();
+    let user = &user.with_scopes(scope);
+
+    let access_token = gen_access_token(
+        user,
+        &app_id,
+        scope,
+        access_expires_in,
+        private_key.as_bytes(),
+    )?;
+    let refresh_token = gen_refresh_token(
+        user,
+        &app_id,
+        scope,
+        family,
+        auth_time,
+        refresh_exp,
+        private_key.as_bytes(),
+    )?;
 
-    let user = verify_token(refresh_token, public_key.as_bytes())?;
     let user_id = user.user_id;
 
     let token_key = format!("{app_id}:{user_id}");
 
-    let token_valid: Option<String> = state
+    state
         .redis
-        .zscore(&token_key, refresh_token)
-        .map_err(|_| Error::RedisGetFail)?;
+        .zadd::<_, _, _, ()>(&token_key, &refresh_token, now)
+        .map_err(|_| Error::RedisSetFail)?;
 
-    token_valid.ok_or(Error::RedisGetEmpty)?;
+    state
+        .redis
+        .expire::<_, ()>(&token_key, policy.refresh_ttl as i64)
+        .map_err(|_| Error::RedisExpireFail)?;
+
+    let family_key = format!("{app_id}:family:{family}");
 
     state
         .redis
-        .zrem(&token_key, refresh_token)
-        .map_err(|_| Error::RedisDelFail)?;
+        .set::<_, _, ()>(&family_key, &refresh_token)
+        .map_err(|_| Error::RedisSetFail)?;
 
-    let private_key = get_private_key(&state.pg, uuid).await?;
+    state
+        .redis
+        .expire::<_, ()>(&family_key, refresh_expires_in as i64)
+        .map_err(|_| Error::RedisExpireFail)?;
+
+    Ok(Tokens {
+        access_token,
+        refresh_token,
+        access_expires_in,
+        refresh_expires_in,
+        scope: scope.map(str::to_string),
+    })
+}
+
+/// Verifies an access token, rejecting refresh tokens and revoked `jti`s.
+pub async fn verify_access_token(
+    state: &mut AppState,
+    app_id: &str,
+    token: &str,
+) -> Result<Claims> {
+    let uuid = Uuid::from_str(app_id).map_err(|_| Error::UuidFail)?;
+    let public_keys = get_public_keys(&state.pg, uuid).await?;
 
-    let refresh_token = gen_refresh_token(&user, &app_id, private_key.as_bytes())?;
-    let access_token = gen_access_token(&user, &app_id, private_key.as_bytes())?;
+    let claims = verify_claims(token, app_id, &public_keys)?;
 
+    if claims.token_use != TokenUse::Access {
+        return Err(Error::JwtInvalidToken);
+    }
+
+    if is_revoked(state, app_id, &claims.jti)? {
+        return Err(Error::JwtInvalidToken);
+    }
+
+    Ok(claims)
+}
+
+pub fn is_revoked(state: &mut AppState, app_id: &str, jti: &str) -> Result<bool> {
     state
         .redis
-        .zadd(&token_key, &refresh_token, 1)
+        .exists(format!("{app_id}:revoked:{jti}"))
+        .map_err(|_| Error::RedisGetFail)
+}
+
+/// Denylists an access token's `jti` until the token would have expired anyway.
+pub fn revoke_access_token(state: &mut AppState, app_id: &str, claims: &Claims) -> Result<()> {
+    let ttl = claims.exp.saturating_sub(now()?).max(1) as i64;
+    let key = format!("{app_id}:revoked:{}", claims.jti);
+
+    state
+        .redis
+        .set::<_, _, ()>(&key, 1)
         .map_err(|_| Error::RedisSetFail)?;
 
     state
         .redis
-        .expire(&refresh_token, 60 * 60 * 24 * 3)
+        .expire::<_, ()>(&key, ttl)
         .map_err(|_| Error::RedisExpireFail)?;
 
-    let mut refresh_cookie = Cookie::build("refresh", refresh_token)
+    Ok(())
+}
+
+/// Ends the session a refresh token belongs to: its whole family, so a successor minted
+/// from it stops refreshing too, and the grace window that would hand that successor out.
+pub fn revoke_refresh_token(
+    state: &mut AppState,
+    app_id: &str,
+    claims: &Claims,
+    refresh_token: &str,
+) -> Result<()> {
+    let user_id = claims
+        .sub
+        .parse::<i32>()
+        .map_err(|_| Error::JwtInvalidToken)?;
+
+    state
+        .redis
+        .zrem::<_, _, ()>(format!("{app_id}:{user_id}"), refresh_token)
+        .map_err(|_| Error::RedisDelFail)?;
+
+    if let Some(family) = &claims.family {
+        revoke_family(state, app_id, user_id, family)?;
+    }
+
+    // tokens without a `jti` are told apart by the token itself, as when rotating
+    let token_id = match claims.jti.as_str() {
+        "" => refresh_token,
+        jti => jti,
+    };
+
+    state
+        .redis
+        .del::<_, ()>(format!("{app_id}:grace:{token_id}"))
+        .map_err(|_| Error::RedisDelFail)?;
+
+    Ok(())
+}
+
+/// Sets the token cookies, expiring each along with its token.
+pub fn set_token_cookies(cookies: &Cookies, tokens: &Tokens, access_http_only: bool) {
+    let mut refresh_cookie = Cookie::build("refresh", tokens.refresh_token.clone())
         .path("/")
         .same_site(SameSite::Lax)
-        .max_age(Duration::seconds(60 * 60 * 24 * 3))
+        .max_age(Duration::seconds(tokens.refresh_expires_in as i64))
         .http_only(true);
 
-    let mut access_cookie = Cookie::build("access", access_token)
+    let mut access_cookie = Cookie::build("access", tokens.access_token.clone())
         .path("/")
         .same_site(SameSite::Lax)
-        .max_age(Duration::seconds(60 * 5))
-        .http_only(false);
+        .max_age(Duration::seconds(tokens.access_expires_in as i64))
+        .http_only(access_http_only);
 
     if env::var("DEV").is_err() {
         let domain = env::var("BASE_DOMAIN").unwrap();
@@ -174,8 +809,6 @@ async fn refresh_tokens(
 
     cookies.add(refresh_cookie);
     cookies.add(access_cookie);
-
-    Ok(())
 }
 
 #[derive(Serialize)]
@@ -187,16 +820,19 @@ async fn logout(
     cookies: Cookies,
     Path(app_id): Path<String>,
     State(mut state): State<AppState>,
+    headers: HeaderMap,
+    body: Option<Json<RefreshRequest>>,
 ) -> Result<Json<Status>> {
-    let refresh_cookie = &cookies.get("refresh").ok_or(Error::AuthMissingCookie)?;
+    let body = body.map(|Json(body)| body);
 
-    let refresh_token = refresh_cookie.value();
+    let (refresh_token, _) = refresh_token_from(&cookies, &headers, body)?;
+    let refresh_token = refresh_token.as_str();
 
     let uuid = Uuid::from_str(&app_id).map_err(|_| Error::UuidFail)?;
-    let public_key = get_public_key(&state.pg, uuid).await?;
+    let public_keys = get_public_keys(&state.pg, uuid).await?;
 
-    let user = verify_token(refresh_token, public_key.as_bytes())?;
-    let user_id = user.user_id;
+    let claims = verify_claims(refresh_token, &app_id, &public_keys)?;
+    let user_id = claims.user.as_ref().ok_or(Error::JwtInvalidToken)?.user_id;
 
     let token_key = format!("{app_id}:{user_id}");
 
@@ -207,10 +843,7 @@ async fn logout(
 
     token_valid.ok_or(Error::RedisGetEmpty)?;
 
-    state
-        .redis
-        .zrem(&token_key, refresh_token)
-        .map_err(|_| Error::RedisDelFail)?;
+    revoke_refresh_token(&mut state, &app_id, &claims, refresh_token)?;
 
     Ok(Json(Status {
         status: "success".to_string(),
@@ -0,0 +1,467 @@
+use std::str::FromStr;
+
+use axum::extract::{Path, Query, State};
+use axum::response::{IntoResponse, Redirect};
+use axum::routing::{get, post};
+use axum::{Form, Json, Router};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use http::header::{AUTHORIZATION, CACHE_CONTROL};
+use http::HeaderMap;
+use oauth2::url::form_urlencoded::Serializer;
+use redis::Commands;
+use serde::{Deserialize, Serialize};
+use sqlx::{types::Uuid, PgPool};
+
+use crate::api::auth::device::{poll_device_code, DevicePoll, DEVICE_CODE_GRANT};
+use crate::api::auth::upstream::login_path;
+use crate::api::auth::{
+    check_pkce, client_redirect, is_revoked, issue_tokens, redeem_code, revoke_access_token,
+    revoke_refresh_token, rotate_tokens, Tokens,
+};
+use crate::db::app::{get_app, get_session_policy, validate_redirect_uri, verify_client_secret};
+use crate::db::key::{get_private_key, get_public_keys};
+use crate::db::user::get_user_by_id;
+use crate::error::{Error, Result};
+use crate::jwt::{gen_id_token, gen_service_token, now, verify_claims, TokenUse};
+use crate::state::AppState;
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/:app_id/authorize", get(authorize))
+        .route("/:app_id/oauth/token", post(token))
+        .route("/:app_id/introspect", post(introspect))
+        .route("/:app_id/revoke", post(revoke))
+}
+
+#[derive(Deserialize)]
+struct AuthorizeQuery {
+    response_type: String,
+    client_id: String,
+    redirect_uri: String,
+    state: Option<String>,
+    scope: Option<String>,
+    nonce: Option<String>,
+    code_challenge: Option<String>,
+    code_challenge_method: Option<String>,
+    provider: Option<String>,
+}
+
+/// RFC 6749 authorization endpoint, hands off to the chosen provider's login or, without
+/// one, to the hosted login page. Once the client and redirect uri check out, errors are
+/// sent back to the client's redirect uri, as RFC 6749 section 4.1.2.1 has it.
+async fn authorize(
+    Path(app_id): Path<String>,
+    State(state): State<AppState>,
+    Query(query): Query<AuthorizeQuery>,
+) -> Result<Redirect> {
+    if query.client_id != app_id {
+        return Err(Error::OAuthInvalidClient);
+    }
+
+    let uuid = Uuid::from_str(&app_id).map_err(|_| Error::UuidFail)?;
+
+    validate_redirect_uri(&state.pg, uuid, &query.redirect_uri).await?;
+
+    let error = |error: &str| {
+        Ok(Redirect::to(&client_redirect(
+            &query.redirect_uri,
+            &[("error", error)],
+            query.state.as_deref(),
+        )))
+    };
+
+    if query.response_type != "code" {
+        return error("unsupported_response_type");
+    }
+
+    // checked before the provider login so a bad challenge is caught at authorize time
+    let pkce = check_pkce(
+        &state.pg,
+        uuid,
+        &query.code_challenge,
+        &query.code_challenge_method,
+    )
+    .await;
+
+    match pkce {
+        Err(Error::OAuthInvalidRequest | Error::AuthInvalidParams | Error::AuthPkceRequired) => {
+            return error("invalid_request");
+        }
+        pkce => pkce?,
+    }
+
+    let login_path = match query.provider.as_deref() {
+        Some(provider) => match login_path(&state.pg, provider, &app_id).await? {
+            Some(login_path) => login_path,
+            None => return error("invalid_request"),
+        },
+        // without a provider the user picks one on the hosted login page
+        None => format!("/api/auth/{app_id}/login"),
+    };
+
+    let mut login = Serializer::new(String::new());
+
+    login.append_pair("redirect_uri", &query.redirect_uri);
+
+    let optional = [
+        ("state", &query.state),
+        ("scope", &query.scope),
+        ("nonce", &query.nonce),
+        ("code_challenge", &query.code_challenge),
+        ("code_challenge_method", &query.code_challenge_method),
+    ];
+
+    for (key, value) in optional {
+        if let Some(value) = value {
+            login.append_pair(key, value);
+        }
+    }
+
+    Ok(Redirect::to(&format!("{login_path}?{}", login.finish())))
+}
+
+#[derive(Deserialize)]
+struct TokenForm {
+    grant_type: String,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+    code: Option<String>,
+    redirect_uri: Option<String>,
+    code_verifier: Option<String>,
+    refresh_token: Option<String>,
+    device_code: Option<String>,
+}
+
+#[derive(Serialize)]
+struct TokenResponse {
+    access_token: String,
+    token_type: &'static str,
+    expires_in: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    refresh_token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id_token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scope: Option<String>,
+}
+
+impl TokenResponse {
+    fn new(tokens: Tokens, id_token: Option<String>) -> Self {
+        Self {
+            access_token: tokens.access_token,
+            token_type: "Bearer",
+            expires_in: tokens.access_expires_in,
+            refresh_token: Some(tokens.refresh_token),
+            id_token,
+            scope: tokens.scope,
+        }
+    }
+}
+
+/// RFC 6749 token endpoint for the `authorization_code`, `refresh_token` and
+/// `client_credentials` grants, and RFC 8628 device code polling.
+async fn token(
+    Path(app_id): Path<String>,
+    State(mut state): State<AppState>,
+    headers: HeaderMap,
+    Form(form): Form<TokenForm>,
+) -> Result<impl IntoResponse> {
+    let authenticated = authenticate_client(
+        &state.pg,
+        &app_id,
+        &headers,
+        form.client_id.as_deref(),
+        form.client_secret.as_deref(),
+    )
+    .await?;
+
+    let uuid = Uuid::from_str(&app_id).map_err(|_| Error::UuidFail)?;
+
+    let response = match form.grant_type.as_str() {
+        "authorization_code" => {
+            let code = form.code.ok_or(Error::OAuthInvalidRequest)?;
+            let redirect_uri = form.redirect_uri.ok_or(Error::OAuthInvalidRequest)?;
+
+            let (user, auth_code) = redeem_code(
+                &mut state,
+                &app_id,
+                &code,
+                Some(&redirect_uri),
+                form.code_verifier.as_deref(),
+            )
+            .await
+            .map_err(invalid_grant)?;
+
+            let tokens =
+                issue_tokens(&mut state, &app_id, &user, auth_code.scope.as_deref()).await?;
+
+            let id_token = if auth_code.has_scope("openid") {
+                let private_key = get_private_key(&state.pg, uuid).await?;
+
+                Some(gen_id_token(
+                    &user,
+                    &app_id,
+                    auth_code.scope.as_deref(),
+                    auth_code.nonce,
+                    auth_code.auth_time,
+                    tokens.access_expires_in,
+                    private_key.as_bytes(),
+                )?)
+            } else {
+                None
+            };
+
+            TokenResponse::new(tokens, id_token)
+        }
+        "refresh_token" => {
+            let refresh_token = form.refresh_token.ok_or(Error::OAuthInvalidRequest)?;
+
+            let tokens = rotate_tokens(&mut state, &app_id, &refresh_token)
+                .await
+                .map_err(invalid_grant)?;
+
+            TokenResponse::new(tokens, None)
+        }
+        "client_credentials" => {
+            if !authenticated {
+                return Err(Error::OAuthInvalidClient);
+            }
+
+            let private_key = get_private_key(&state.pg, uuid).await?;
+            let access_ttl = get_session_policy(&state.pg, uuid).await?.access_ttl as usize;
+
+            TokenResponse {
+                access_token: gen_service_token(&app_id, access_ttl, private_key.as_bytes())?,
+                token_type: "Bearer",
+                expires_in: access_ttl,
+                refresh_token: None,
+                id_token: None,
+                scope: None,
+            }
+        }
+        DEVICE_CODE_GRANT => {
+            let device_code = form.device_code.ok_or(Error::OAuthInvalidRequest)?;
+
+            let grant = match poll_device_code(&mut state, &app_id, &device_code)? {
+                DevicePoll::Approved(grant) => grant,
+                DevicePoll::Pending => return Err(Error::OAuthAuthorizationPending),
+            };
+
+            let user = get_user_by_id(&state.pg, uuid, grant.user_id.ok_or(Error::PgNone)?)
+                .await?
+                .ok_or(Error::OAuthInvalidGrant)?;
+
+            let tokens = issue_tokens(&mut state, &app_id, &user, grant.scope.as_deref()).await?;
+
+            let openid = grant
+                .scope
+                .as_ref()
+                .is_some_and(|scopes| scopes.split(' ').any(|s| s == "openid"));
+
+            let id_token = if openid {
+                let private_key = get_private_key(&state.pg, uuid).await?;
+
+                Some(gen_id_token(
+                    &user,
+                    &app_id,
+                    grant.scope.as_deref(),
+                    None,
+                    grant.auth_time.unwrap_or(now()?),
+                    tokens.access_expires_in,
+                    private_key.as_bytes(),
+                )?)
+            } else {
+                None
+            };
+
+            TokenResponse::new(tokens, id_token)
+        }
+        _ => return Err(Error::OAuthUnsupportedGrantType),
+    };
+
+    Ok(([(CACHE_CONTROL, "no-store")], Json(response)))
+}
+
+// `token_type_hint` isn't needed, tokens carry their own `token_use`
+#[derive(Deserialize)]
+struct IntrospectForm {
+    token: String,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+}
+
+#[derive(Serialize, Default)]
+struct Introspection {
+    active: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sub: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token_type: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exp: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scope: Option<String>,
+}
+
+/// RFC 7662 token introspection, only available to confidential clients.
+async fn introspect(
+    Path(app_id): Path<String>,
+    State(mut state): State<AppState>,
+    headers: HeaderMap,
+    Form(form): Form<IntrospectForm>,
+) -> Result<Json<Introspection>> {
+    let authenticated = authenticate_client(
+        &state.pg,
+        &app_id,
+        &headers,
+        form.client_id.as_deref(),
+        form.client_secret.as_deref(),
+    )
+    .await?;
+
+    if !authenticated {
+        return Err(Error::OAuthInvalidClient);
+    }
+
+    let uuid = Uuid::from_str(&app_id).map_err(|_| Error::UuidFail)?;
+    let public_keys = get_public_keys(&state.pg, uuid).await?;
+
+    let claims = match verify_claims(&form.token, &app_id, &public_keys) {
+        Ok(claims) => claims,
+        _ => return Ok(Json(Introspection::default())),
+    };
+
+    let token_type = match claims.token_use {
+        TokenUse::Access => {
+            if is_revoked(&mut state, &app_id, &claims.jti)? {
+                return Ok(Json(Introspection::default()));
+            }
+
+            "access_token"
+        }
+        TokenUse::Refresh => {
+            let token_key = format!("{app_id}:{}", claims.sub);
+
+            let in_session: Option<String> = state
+                .redis
+                .zscore(&token_key, &form.token)
+                .map_err(|_| Error::RedisGetFail)?;
+
+            if in_session.is_none() {
+                return Ok(Json(Introspection::default()));
+            }
+
+            "refresh_token"
+        }
+    };
+
+    Ok(Json(Introspection {
+        active: true,
+        sub: Some(claims.sub),
+        client_id: Some(claims.client_id),
+        token_type: Some(token_type),
+        exp: Some(claims.exp),
+        scope: claims.scope,
+    }))
+}
+
+// like introspection, `token_type_hint` is redundant with the token's `token_use`
+#[derive(Deserialize)]
+struct RevokeForm {
+    token: String,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+}
+
+/// RFC 7009 token revocation. Unknown or invalid tokens are not an error.
+async fn revoke(
+    Path(app_id): Path<String>,
+    State(mut state): State<AppState>,
+    headers: HeaderMap,
+    Form(form): Form<RevokeForm>,
+) -> Result<()> {
+    authenticate_client(
+        &state.pg,
+        &app_id,
+        &headers,
+        form.client_id.as_deref(),
+        form.client_secret.as_deref(),
+    )
+    .await?;
+
+    let uuid = Uuid::from_str(&app_id).map_err(|_| Error::UuidFail)?;
+    let public_keys = get_public_keys(&state.pg, uuid).await?;
+
+    let claims = match verify_claims(&form.token, &app_id, &public_keys) {
+        Ok(claims) => claims,
+        _ => return Ok(()),
+    };
+
+    match claims.token_use {
+        // tokens from before `jti` can only be revoked as the refresh token they may be
+        TokenUse::Access if !claims.jti.is_empty() => {
+            revoke_access_token(&mut state, &app_id, &claims)?
+        }
+        _ => revoke_refresh_token(&mut state, &app_id, &claims, &form.token)?,
+    }
+
+    Ok(())
+}
+
+/// Checks client credentials sent with HTTP Basic or in the form body. Apps with a client
+/// secret must authenticate, public clients may only identify themselves.
+/// Returns whether the client authenticated with its secret.
+pub async fn authenticate_client(
+    pool: &PgPool,
+    app_id: &str,
+    headers: &HeaderMap,
+    client_id: Option<&str>,
+    client_secret: Option<&str>,
+) -> Result<bool> {
+    let basic = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Basic "))
+        .and_then(|value| STANDARD.decode(value).ok())
+        .and_then(|value| String::from_utf8(value).ok());
+
+    let (client_id, client_secret) = match &basic {
+        Some(credentials) => {
+            let (id, secret) = credentials
+                .split_once(':')
+                .ok_or(Error::OAuthInvalidClient)?;
+
+            (Some(id), Some(secret))
+        }
+        None => (client_id, client_secret),
+    };
+
+    if client_id.is_some_and(|id| id != app_id) {
+        return Err(Error::OAuthInvalidClient);
+    }
+
+    let uuid = Uuid::from_str(app_id).map_err(|_| Error::UuidFail)?;
+
+    match client_secret {
+        Some(secret) if verify_client_secret(pool, uuid, secret).await? => Ok(true),
+        Some(_) => Err(Error::OAuthInvalidClient),
+        None if get_app(pool, uuid).await?.confidential => Err(Error::OAuthInvalidClient),
+        None => Ok(false),
+    }
+}
+
+/// Reports a code or refresh token that can't be redeemed as `invalid_grant`.
+fn invalid_grant(error: Error) -> Error {
+    match error {
+        Error::RedisGetEmpty
+        | Error::PgNone
+        | Error::JwtInvalidToken
+        | Error::AuthPkceRequired
+        | Error::AuthPkceMismatch
+        | Error::AuthRedirectMismatch
+        | Error::AuthRefreshReuse => Error::OAuthInvalidGrant,
+        error => error,
+    }
+}
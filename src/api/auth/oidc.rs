@@ -0,0 +1,148 @@
+use std::str::FromStr;
+
+use axum::extract::{Path, State};
+use axum::routing::get;
+use axum::{Json, Router};
+use http::HeaderMap;
+use jsonwebtoken::jwk::JwkSet;
+use serde::Serialize;
+use sqlx::types::Uuid;
+
+use crate::api::auth::device::DEVICE_CODE_GRANT;
+use crate::api::auth::{bearer_token, verify_access_token};
+use crate::db::key::get_public_keys;
+use crate::db::user::get_user_by_id;
+use crate::error::{Error, Result};
+use crate::jwt::{issuer, public_jwk, scope_allows, Profile};
+use crate::state::AppState;
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route(
+            "/:app_id/.well-known/openid-configuration",
+            get(openid_configuration),
+        )
+        .route("/:app_id/jwks.json", get(jwks))
+        .route("/:app_id/userinfo", get(userinfo).post(userinfo))
+}
+
+#[derive(Serialize)]
+struct OpenIdConfiguration {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    userinfo_endpoint: String,
+    introspection_endpoint: String,
+    revocation_endpoint: String,
+    device_authorization_endpoint: String,
+    jwks_uri: String,
+    response_types_supported: Vec<&'static str>,
+    grant_types_supported: Vec<&'static str>,
+    code_challenge_methods_supported: Vec<&'static str>,
+    token_endpoint_auth_methods_supported: Vec<&'static str>,
+    subject_types_supported: Vec<&'static str>,
+    id_token_signing_alg_values_supported: Vec<&'static str>,
+    scopes_supported: Vec<&'static str>,
+    claims_supported: Vec<&'static str>,
+}
+
+async fn openid_configuration(
+    Path(app_id): Path<String>,
+    State(state): State<AppState>,
+) -> Result<Json<OpenIdConfiguration>> {
+    let uuid = Uuid::from_str(&app_id).map_err(|_| Error::UuidFail)?;
+
+    // make sure the app exists before advertising anything for it
+    get_public_keys(&state.pg, uuid).await?;
+
+    let issuer = issuer(&app_id);
+
+    Ok(Json(OpenIdConfiguration {
+        authorization_endpoint: format!("{issuer}/authorize"),
+        token_endpoint: format!("{issuer}/oauth/token"),
+        userinfo_endpoint: format!("{issuer}/userinfo"),
+        introspection_endpoint: format!("{issuer}/introspect"),
+        revocation_endpoint: format!("{issuer}/revoke"),
+        device_authorization_endpoint: format!("{issuer}/device_authorization"),
+        jwks_uri: format!("{issuer}/jwks.json"),
+        issuer,
+        response_types_supported: vec!["code"],
+        grant_types_supported: vec![
+            "authorization_code",
+            "refresh_token",
+            "client_credentials",
+            DEVICE_CODE_GRANT,
+        ],
+        code_challenge_methods_supported: vec!["S256"],
+        token_endpoint_auth_methods_supported: vec![
+            "none",
+            "client_secret_basic",
+            "client_secret_post",
+        ],
+        subject_types_supported: vec!["public"],
+        id_token_signing_alg_values_supported: vec!["RS256"],
+        scopes_supported: vec![
+            "openid", "profile", "discord", "steam", "google", "github", "admin",
+        ],
+        claims_supported: vec![
+            "iss",
+            "sub",
+            "aud",
+            "iat",
+            "exp",
+            "auth_time",
+            "nonce",
+            "name",
+            "preferred_username",
+            "picture",
+        ],
+    }))
+}
+
+async fn jwks(Path(app_id): Path<String>, State(state): State<AppState>) -> Result<Json<JwkSet>> {
+    let uuid = Uuid::from_str(&app_id).map_err(|_| Error::UuidFail)?;
+
+    let keys = get_public_keys(&state.pg, uuid)
+        .await?
+        .iter()
+        .map(|key| public_jwk(key.as_bytes()))
+        .collect::<Result<_>>()?;
+
+    Ok(Json(JwkSet { keys }))
+}
+
+#[derive(Serialize)]
+struct UserInfo {
+    sub: String,
+    #[serde(flatten)]
+    profile: Option<Profile>,
+}
+
+/// OIDC UserInfo, the current profile for the user behind a bearer access token.
+async fn userinfo(
+    Path(app_id): Path<String>,
+    State(mut state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<UserInfo>> {
+    let token = bearer_token(&headers).ok_or(Error::AuthMissingBearer)?;
+
+    let claims = verify_access_token(&mut state, &app_id, token).await?;
+
+    // service tokens have no user behind them
+    let user_id = claims
+        .user
+        .map(|user| user.user_id)
+        .ok_or(Error::JwtInvalidToken)?;
+
+    let uuid = Uuid::from_str(&app_id).map_err(|_| Error::UuidFail)?;
+
+    let user = get_user_by_id(&state.pg, uuid, user_id)
+        .await?
+        .ok_or(Error::PgNone)?;
+
+    Ok(Json(UserInfo {
+        sub: claims.sub,
+        profile: scope_allows(claims.scope.as_deref(), "profile")
+            .then(|| Profile::from_user(&user)),
+    }))
+}
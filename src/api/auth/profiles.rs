@@ -0,0 +1,274 @@
+use std::collections::HashMap;
+use std::env;
+use std::time::Duration;
+
+use redis::Commands;
+use sqlx::types::Uuid;
+use tokio::time::sleep;
+
+use crate::api::auth::discord::fetch_user;
+use crate::api::auth::steam::{player_summaries, steam_api_key};
+use crate::db::user::{get_stale_identities, touch_identities, update_identity, StaleIdentity};
+use crate::error::{Error, Result};
+use crate::state::AppState;
+
+/// Steam's player summaries take at most this many ids per request.
+const STEAM_BATCH: usize = 100;
+
+/// Refreshes stale profiles every `PROFILE_REFRESH_INTERVAL` seconds, an hour by default.
+pub async fn refresh_profiles_forever(mut state: AppState) {
+    let interval = env::var("PROFILE_REFRESH_INTERVAL")
+        .ok()
+        .and_then(|interval| interval.parse().ok())
+        .unwrap_or(60 * 60);
+
+    loop {
+        match refresh_stale_profiles(&mut state).await {
+            Ok(refreshed) => println!("PROFILES - refreshed {refreshed}"),
+            Err(e) => println!("PROFILES - refresh failed: {e:?}"),
+        }
+
+        sleep(Duration::from_secs(interval)).await;
+    }
+}
+
+/// Keeps the Discord and Steam profiles of signed in users current between their logins.
+/// Profiles older than `PROFILE_MAX_AGE` seconds, a day by default, are looked up again
+/// for users that still hold a refresh token. Discord users are only refreshed when
+/// `DISCORD_BOT_TOKEN` is set, and Steam users of apps without a web api key are skipped.
+/// Returns how many profiles were refreshed.
+pub async fn refresh_stale_profiles(state: &mut AppState) -> Result<usize> {
+    let max_age = env::var("PROFILE_MAX_AGE")
+        .ok()
+        .and_then(|max_age| max_age.parse().ok())
+        .unwrap_or(60 * 60 * 24);
+
+    let client = reqwest::Client::new();
+    let mut refreshed = 0;
+
+    if let Ok(bot_token) = env::var("DISCORD_BOT_TOKEN") {
+        let stale = get_stale_identities(&state.pg, "discord", max_age).await?;
+
+        for identity in active(state, stale)? {
+            match fetch_user(&client, &bot_token, &identity.subject).await {
+                Ok(account) => {
+                    match update_identity(&state.pg, identity.app_id, "discord", &account).await {
+                        Ok(()) => refreshed += 1,
+                        Err(e) => println!(
+                            "PROFILES - discord user {} not saved: {e:?}",
+                            identity.subject
+                        ),
+                    }
+                }
+                Err(e) => println!(
+                    "PROFILES - discord user {} not refreshed: {e:?}",
+                    identity.subject
+                ),
+            }
+        }
+    }
+
+    let stale = get_stale_identities(&state.pg, "steam", max_age).await?;
+
+    // apps may have their own api key, so users are looked up per app
+    let mut steam_ids: HashMap<Uuid, Vec<String>> = HashMap::new();
+
+    for identity in active(state, stale)? {
+        steam_ids
+            .entry(identity.app_id)
+            .or_default()
+            .push(identity.subject);
+    }
+
+    for (app_id, steam_ids) in steam_ids {
+        let api_key = match steam_api_key(state, &app_id.to_string()).await {
+            Ok(api_key) => api_key,
+            Err(Error::AuthProviderUnconfigured) => {
+                println!("PROFILES - steam users of app {app_id} skipped, no api key");
+                continue;
+            }
+            Err(e) => {
+                println!("PROFILES - steam users of app {app_id} not refreshed: {e:?}");
+                continue;
+            }
+        };
+
+        for batch in steam_ids.chunks(STEAM_BATCH) {
+            let accounts = match player_summaries(&client, &api_key, batch).await {
+                Ok(accounts) => accounts,
+                Err(e) => {
+                    println!("PROFILES - steam users of app {app_id} not refreshed: {e:?}");
+                    continue;
+                }
+            };
+
+            // private or deleted profiles are left out, so they wait another max age too
+            let missing: Vec<String> = batch
+                .iter()
+                .filter(|steam_id| {
+                    !accounts
+                        .iter()
+                        .any(|account| account.id.as_ref() == Some(steam_id))
+                })
+                .cloned()
+                .collect();
+
+            if let Err(e) = touch_identities(&state.pg, app_id, "steam", &missing).await {
+                println!("PROFILES - missing steam users of app {app_id} not saved: {e:?}");
+            }
+
+            for account in accounts {
+                match update_identity(&state.pg, app_id, "steam", &account).await {
+                    Ok(()) => refreshed += 1,
+                    Err(e) => println!("PROFILES - steam user {:?} not saved: {e:?}", account.id),
+                }
+            }
+        }
+    }
+
+    Ok(refreshed)
+}
+
+/// Keeps the identities of users with a live session, their refresh tokens expiring with it.
+fn active(state: &mut AppState, identities: Vec<StaleIdentity>) -> Result<Vec<StaleIdentity>> {
+    let mut active = Vec::new();
+
+    for identity in identities {
+        let signed_in: bool = state
+            .redis
+            .exists(format!("{}:{}", identity.app_id, identity.user_id))
+            .map_err(|_| Error::RedisGetFail)?;
+
+        if signed_in {
+            active.push(identity);
+        }
+    }
+
+    Ok(active)
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::types::Uuid;
+
+    use super::refresh_stale_profiles;
+    use crate::api::auth::mint_tokens;
+    use crate::api::auth::stand_in::test_state;
+    use crate::db::app::{create_app, get_session_policy};
+    use crate::db::user::{create_user, get_stale_identities, get_user_by_id, Account, User};
+    use crate::jwt::{gen_jti, now};
+    use crate::state::AppState;
+
+    /// A user whose profile was last refreshed two days ago.
+    async fn stale_user(
+        state: &mut AppState,
+        app_id: Uuid,
+        provider: &str,
+        id: &str,
+        signed_in: bool,
+    ) -> i32 {
+        let account = Account {
+            id: Some(id.to_string()),
+            avatar: Some("old".to_string()),
+            username: Some("old".to_string()),
+        };
+
+        let user_id = create_user(&state.pg, app_id, provider, &account)
+            .await
+            .unwrap();
+
+        sqlx::query("update user_identities set updated_at = now() - interval '2 days' where app_id = $1 and user_id = $2")
+            .bind(app_id)
+            .bind(user_id)
+            .execute(&state.pg)
+            .await
+            .unwrap();
+
+        if signed_in {
+            let user = user(state, app_id, user_id).await;
+            let policy = get_session_policy(&state.pg, app_id).await.unwrap();
+
+            mint_tokens(
+                state,
+                &app_id.to_string(),
+                &user,
+                None,
+                &gen_jti(),
+                now().unwrap(),
+                &policy,
+            )
+            .await
+            .unwrap();
+        }
+
+        user_id
+    }
+
+    async fn user(state: &AppState, app_id: Uuid, user_id: i32) -> User {
+        get_user_by_id(&state.pg, app_id, user_id)
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_POSTGRES_URL"]
+    async fn refreshes_signed_in_users() {
+        let mut state = test_state().await;
+        let app_id = create_app(&state.pg, "profiles".to_string()).await.unwrap();
+
+        let discord = stale_user(&mut state, app_id, "discord", "1001", true).await;
+        let signed_out = stale_user(&mut state, app_id, "discord", "1002", false).await;
+        let steam = stale_user(&mut state, app_id, "steam", "2001", true).await;
+
+        refresh_stale_profiles(&mut state).await.unwrap();
+
+        let discord = user(&state, app_id, discord).await.discord;
+        assert_eq!(discord.username.as_deref(), Some("user-1001"));
+        assert_eq!(discord.avatar.as_deref(), Some("avatar-1001"));
+
+        let signed_out = user(&state, app_id, signed_out).await.discord;
+        assert_eq!(signed_out.username.as_deref(), Some("old"));
+
+        let steam = user(&state, app_id, steam).await.steam;
+        assert_eq!(steam.username.as_deref(), Some("player-2001"));
+        assert_eq!(steam.avatar.as_deref(), Some("hash-2001"));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_POSTGRES_URL"]
+    async fn carries_on_past_unknown_users() {
+        let mut state = test_state().await;
+        let app_id = create_app(&state.pg, "profiles".to_string()).await.unwrap();
+
+        let missing_discord = stale_user(&mut state, app_id, "discord", "missing-1003", true).await;
+        let discord = stale_user(&mut state, app_id, "discord", "1004", true).await;
+        let missing_steam = stale_user(&mut state, app_id, "steam", "missing-2002", true).await;
+        let steam = stale_user(&mut state, app_id, "steam", "2003", true).await;
+
+        refresh_stale_profiles(&mut state).await.unwrap();
+
+        let missing_discord = user(&state, app_id, missing_discord).await.discord;
+        assert_eq!(missing_discord.username.as_deref(), Some("old"));
+
+        let discord = user(&state, app_id, discord).await.discord;
+        assert_eq!(discord.username.as_deref(), Some("user-1004"));
+
+        let missing_steam = user(&state, app_id, missing_steam).await.steam;
+        assert_eq!(missing_steam.username.as_deref(), Some("old"));
+
+        let steam = user(&state, app_id, steam).await.steam;
+        assert_eq!(steam.username.as_deref(), Some("player-2003"));
+
+        // steam's misses wait out another max age, discord's are tried again
+        let stale_steam = get_stale_identities(&state.pg, "steam", 60).await.unwrap();
+        assert!(!stale_steam.iter().any(|identity| identity.app_id == app_id));
+
+        let stale_discord = get_stale_identities(&state.pg, "discord", 60)
+            .await
+            .unwrap();
+        assert!(stale_discord
+            .iter()
+            .any(|identity| identity.app_id == app_id && identity.subject == "missing-1003"));
+    }
+}
@@ -0,0 +1,282 @@
+//! Local stand-ins for Discord, Steam and redis, for tests against the Postgres database
+//! given as `TEST_POSTGRES_URL`. User ids starting with `missing` are unknown to both
+//! providers, every other user's profile is named after their id.
+
+use std::collections::HashMap;
+use std::env;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{mpsc, Arc, Mutex, OnceLock};
+use std::thread;
+
+use axum::extract::{Path, Query};
+use axum::routing::{get, post};
+use axum::{Form, Json, Router};
+use http::{HeaderMap, StatusCode};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+
+use crate::api::auth::bearer_token;
+use crate::state::{discord_client, AppState};
+
+static REDIS_ADDR: OnceLock<SocketAddr> = OnceLock::new();
+
+/// A state whose providers and redis are the stand-ins, on a migrated test database.
+pub async fn test_state() -> AppState {
+    let redis_addr = REDIS_ADDR.get_or_init(start);
+
+    AppState {
+        oauth: discord_client("stand-in".to_string(), "stand-in".to_string()),
+        github: None,
+        redis: redis::Client::open(format!("redis://{redis_addr}")).unwrap(),
+        pg: PgPool::connect(&env::var("TEST_POSTGRES_URL").unwrap())
+            .await
+            .unwrap(),
+    }
+}
+
+/// Migrates the database and starts the stand-ins on a thread of their own, outliving the
+/// runtime of the test that got here first.
+fn start() -> SocketAddr {
+    let http = TcpListener::bind("127.0.0.1:0").unwrap();
+    let redis = TcpListener::bind("127.0.0.1:0").unwrap();
+
+    let http_url = format!("http://{}", http.local_addr().unwrap());
+    let redis_addr = redis.local_addr().unwrap();
+
+    env::set_var("BASE_URL", "http://localhost");
+    env::set_var("PRIVATE_KEY_ENC_KEY", "stand-in");
+    env::set_var("DISCORD_API_URL", &http_url);
+    env::set_var("DISCORD_BOT_TOKEN", "stand-in");
+    env::set_var("STEAM_API_URL", &http_url);
+    env::set_var("STEAM_OPENID_URL", format!("{http_url}/openid"));
+    env::set_var("STEAM_API_KEY", "stand-in");
+
+    thread::spawn(move || serve_redis(redis));
+
+    let (migrated, wait) = mpsc::channel();
+
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+
+        runtime.block_on(async {
+            migrate().await;
+            migrated.send(()).unwrap();
+
+            axum::Server::from_tcp(http)
+                .unwrap()
+                .serve(routes().into_make_service())
+                .await
+                .unwrap();
+        });
+    });
+
+    wait.recv().unwrap();
+
+    redis_addr
+}
+
+async fn migrate() {
+    let pool = PgPool::connect(&env::var("TEST_POSTGRES_URL").unwrap())
+        .await
+        .unwrap();
+
+    for extension in ["uuid-ossp", "pgcrypto"] {
+        sqlx::query(&format!(r#"create extension if not exists "{extension}""#))
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+
+    sqlx::migrate!("./migrations").run(&pool).await.unwrap();
+}
+
+fn routes() -> Router {
+    Router::new()
+        .route("/oauth2/token", post(discord_token))
+        .route("/users/@me", get(discord_me))
+        .route("/users/:id", get(discord_user))
+        .route("/openid/login", get(steam_openid))
+        .route(
+            "/ISteamUser/GetPlayerSummaries/v0002/",
+            get(steam_summaries),
+        )
+}
+
+fn discord_profile(id: &str) -> Value {
+    json!({ "id": id, "username": format!("user-{id}"), "avatar": format!("avatar-{id}") })
+}
+
+/// Trades the code for an access token that is the discord user's id.
+async fn discord_token(Form(form): Form<HashMap<String, String>>) -> Json<Value> {
+    Json(json!({ "access_token": form["code"], "token_type": "Bearer" }))
+}
+
+async fn discord_me(headers: HeaderMap) -> Json<Value> {
+    Json(discord_profile(bearer_token(&headers).unwrap()))
+}
+
+async fn discord_user(Path(id): Path<String>) -> Result<Json<Value>, StatusCode> {
+    if id.starts_with("missing") {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    Ok(Json(discord_profile(&id)))
+}
+
+async fn steam_openid() -> &'static str {
+    "ns:http://specs.openid.net/auth/2.0\nis_valid:true\n"
+}
+
+/// Leaves out unknown users, as steam does with deleted ones.
+async fn steam_summaries(Query(query): Query<HashMap<String, String>>) -> Json<Value> {
+    let players: Vec<Value> = query["steamids"]
+        .split(',')
+        .filter(|id| !id.starts_with("missing"))
+        .map(|id| {
+            json!({
+                "steamid": id,
+                "personaname": format!("player-{id}"),
+                "avatarhash": format!("hash-{id}"),
+            })
+        })
+        .collect();
+
+    Json(json!({ "response": { "players": players } }))
+}
+
+/// A redis value, of the two types the server uses.
+enum Entry {
+    String(Vec<u8>),
+    /// Members by score, kept as sent.
+    SortedSet(HashMap<Vec<u8>, Vec<u8>>),
+}
+
+type Store = Arc<Mutex<HashMap<Vec<u8>, Entry>>>;
+
+/// Answers the redis commands the server uses on strings and sorted sets, erroring on the
+/// rest so tests can't pass on commands it doesn't know.
+fn serve_redis(listener: TcpListener) {
+    let store = Store::default();
+
+    for stream in listener.incoming() {
+        let stream = stream.unwrap();
+        let store = store.clone();
+
+        thread::spawn(move || {
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+
+            while let Some(command) = read_command(&mut reader) {
+                if writer.write_all(&reply(&store, &command)).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+fn read_command(reader: &mut BufReader<TcpStream>) -> Option<Vec<Vec<u8>>> {
+    let mut line = String::new();
+    reader.read_line(&mut line).ok()?;
+
+    let count: usize = line.trim_end().strip_prefix('*')?.parse().ok()?;
+
+    (0..count)
+        .map(|_| {
+            line.clear();
+            reader.read_line(&mut line).ok()?;
+
+            let len: usize = line.trim_end().strip_prefix('$')?.parse().ok()?;
+
+            let mut arg = vec![0; len + 2];
+            reader.read_exact(&mut arg).ok()?;
+            arg.truncate(len);
+
+            Some(arg)
+        })
+        .collect()
+}
+
+fn bulk(value: Option<&Vec<u8>>) -> Vec<u8> {
+    match value {
+        Some(value) => [format!("${}\r\n", value.len()).as_bytes(), value, b"\r\n"].concat(),
+        None => b"$-1\r\n".to_vec(),
+    }
+}
+
+fn integer(value: usize) -> Vec<u8> {
+    format!(":{value}\r\n").into_bytes()
+}
+
+const WRONG_TYPE: &[u8] = b"-WRONGTYPE Operation against a key holding the wrong kind of value\r\n";
+
+fn reply(store: &Store, command: &[Vec<u8>]) -> Vec<u8> {
+    let mut store = store.lock().unwrap();
+    let name = command[0].to_ascii_uppercase();
+    let args = &command[1..];
+
+    match (name.as_slice(), args) {
+        (b"GET", [key]) => match store.get(key) {
+            Some(Entry::String(value)) => bulk(Some(value)),
+            Some(_) => WRONG_TYPE.to_vec(),
+            None => bulk(None),
+        },
+        (b"SET", [key, value]) => {
+            store.insert(key.clone(), Entry::String(value.clone()));
+            b"+OK\r\n".to_vec()
+        }
+        (b"DEL", keys) => integer(
+            keys.iter()
+                .filter(|key| store.remove(*key).is_some())
+                .count(),
+        ),
+        (b"EXISTS", keys) => integer(keys.iter().filter(|key| store.contains_key(*key)).count()),
+        (b"EXPIRE", [key, _]) => integer(store.contains_key(key) as usize),
+        (b"ZADD", [key, pairs @ ..]) if !pairs.is_empty() && pairs.len() % 2 == 0 => {
+            let entry = store
+                .entry(key.clone())
+                .or_insert_with(|| Entry::SortedSet(HashMap::new()));
+
+            let Entry::SortedSet(members) = entry else {
+                return WRONG_TYPE.to_vec();
+            };
+
+            let added = pairs
+                .chunks(2)
+                .filter(|pair| members.insert(pair[1].clone(), pair[0].clone()).is_none())
+                .count();
+
+            integer(added)
+        }
+        (b"ZSCORE", [key, member]) => match store.get(key) {
+            Some(Entry::SortedSet(members)) => bulk(members.get(member)),
+            Some(_) => WRONG_TYPE.to_vec(),
+            None => bulk(None),
+        },
+        (b"ZREM", [key, removed @ ..]) => {
+            let Some(Entry::SortedSet(members)) = store.get_mut(key) else {
+                return integer(0);
+            };
+
+            let count = removed
+                .iter()
+                .filter(|member| members.remove(*member).is_some())
+                .count();
+
+            // like redis, emptied sets are gone
+            if members.is_empty() {
+                store.remove(key);
+            }
+
+            integer(count)
+        }
+        // the client announcing itself on connect
+        (b"CLIENT", _) => b"+OK\r\n".to_vec(),
+        _ => format!(
+            "-ERR stand-in doesn't know {}\r\n",
+            String::from_utf8_lossy(&command[0])
+        )
+        .into_bytes(),
+    }
+}
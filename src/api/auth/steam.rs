@@ -4,17 +4,14 @@ use axum::{
     response::Redirect,
     Router,
 };
-use rand::distributions::Alphanumeric;
-use rand::thread_rng;
-use rand::Rng;
-use redis::Commands;
 use serde_json::Value;
-use sqlx::types::Uuid;
 use std::env;
-use std::str::FromStr;
+use tower_cookies::Cookies;
 
-use crate::db::app::validate_redirect_uri;
-use crate::db::user::{create_user, get_user, update_user_steam, Account};
+use crate::api::auth::identity::{
+    app_credentials, begin_login, finish_login, Callback, IdentityProvider, LoginQuery,
+};
+use crate::db::user::Account;
 use crate::error::Error;
 use crate::{error::Result, state::AppState};
 use serde::Deserialize;
@@ -25,35 +22,6 @@ pub fn routes() -> Router<AppState> {
         .route("/redirect", get(auth_redirect))
 }
 
-#[derive(Deserialize)]
-struct LoginQuery {
-    redirect_uri: String,
-}
-
-async fn auth_login(
-    Path(app_id): Path<String>,
-    Query(query): Query<LoginQuery>,
-    State(state): State<AppState>,
-) -> Result<Redirect> {
-    let uuid = Uuid::from_str(&app_id).map_err(|_| Error::UuidFail)?;
-
-    validate_redirect_uri(&state.pg, uuid, &query.redirect_uri).await?;
-
-    let base_url = env::var("BASE_URL").unwrap();
-
-    let return_to = format!(
-        "{}/api/auth/steam/redirect?state={};{}",
-        base_url, query.redirect_uri, app_id
-    );
-
-    let auth_url = format!(
-        r"https://steamcommunity.com/openid/login?openid.ns=http://specs.openid.net/auth/2.0&openid.mode=checkid_setup&openid.return_to={}&openid.realm={}&openid.identity=http://specs.openid.net/auth/2.0/identifier_select&openid.claimed_id=http://specs.openid.net/auth/2.0/identifier_select",
-        return_to, base_url
-    );
-
-    Ok(Redirect::to(&auth_url))
-}
-
 #[derive(Deserialize)]
 struct AuthRequest {
     #[serde(rename = "openid.assoc_handle")]
@@ -74,8 +42,6 @@ struct AuthRequest {
     openid_return_to: String,
     #[serde(rename = "openid.response_nonce")]
     openid_response_nonce: String,
-
-    state: String,
 }
 
 #[derive(Deserialize)]
@@ -85,50 +51,106 @@ struct SteamUser {
     avatarhash: String,
 }
 
-async fn auth_redirect(
-    Query(query): Query<AuthRequest>,
-    State(mut state): State<AppState>,
-) -> Result<Redirect> {
-    let openid_query = format!(
-        r"openid.assoc_handle={}&openid.signed={}&openid.sig={}&openid.ns={}&openid.mode=check_authentication&openid.op_endpoint={}&openid.claimed_id={}&openid.identity={}&openid.return_to={}&openid.response_nonce={}",
-        query.openid_assoc_handle,
-        query.openid_signed,
-        query.openid_sig.replace("+", "%2B"),
-        query.openid_ns,
-        query.openid_op_endpoint,
-        query.openid_claimed_id,
-        query.openid_identity,
-        query.openid_return_to,
-        query.openid_response_nonce.replace("+", "%2B")
-    );
+struct Steam;
 
-    let client = reqwest::Client::new();
+impl IdentityProvider for Steam {
+    type Callback = AuthRequest;
+
+    fn name(&self) -> &str {
+        "steam"
+    }
 
-    let validation = client
-        .get(format!(
-            "https://steamcommunity.com/openid/login?{}",
-            openid_query
+    async fn authorize_url(&self, _: &AppState, _: &str, key: &str) -> Result<String> {
+        let base_url = env::var("BASE_URL").unwrap();
+
+        let return_to = format!("{}/api/auth/steam/redirect?state={}", base_url, key);
+
+        Ok(format!(
+            r"{}/login?openid.ns=http://specs.openid.net/auth/2.0&openid.mode=checkid_setup&openid.return_to={}&openid.realm={}&openid.identity=http://specs.openid.net/auth/2.0/identifier_select&openid.claimed_id=http://specs.openid.net/auth/2.0/identifier_select",
+            openid_url(),
+            return_to,
+            base_url
         ))
-        .send()
-        .await
-        .map_err(|_| Error::AuthUserFetchFail)?
-        .text()
-        .await
-        .map_err(|_| Error::AuthUserParseFail)?;
+    }
+
+    async fn identify(
+        &self,
+        state: &AppState,
+        app_id: &str,
+        query: AuthRequest,
+        _: &str,
+    ) -> Result<Account> {
+        let openid_query = format!(
+            r"openid.assoc_handle={}&openid.signed={}&openid.sig={}&openid.ns={}&openid.mode=check_authentication&openid.op_endpoint={}&openid.claimed_id={}&openid.identity={}&openid.return_to={}&openid.response_nonce={}",
+            query.openid_assoc_handle,
+            query.openid_signed,
+            query.openid_sig.replace("+", "%2B"),
+            query.openid_ns,
+            query.openid_op_endpoint,
+            query.openid_claimed_id,
+            query.openid_identity,
+            query.openid_return_to,
+            query.openid_response_nonce.replace("+", "%2B")
+        );
+
+        let client = reqwest::Client::new();
+
+        let validation = client
+            .get(format!("{}/login?{}", openid_url(), openid_query))
+            .send()
+            .await
+            .map_err(|_| Error::AuthUserFetchFail)?
+            .text()
+            .await
+            .map_err(|_| Error::AuthUserParseFail)?;
+
+        if !validation.contains("is_valid:true") {
+            println!("{validation}");
+            return Err(Error::AuthInvalidParams);
+        }
+
+        let steam_id64 = query
+            .openid_claimed_id
+            .replace("https://steamcommunity.com/openid/id/", "");
+
+        let api_key = steam_api_key(state, app_id).await?;
 
-    if !validation.contains("is_valid:true") {
-        println!("{validation}");
-        return Err(Error::AuthInvalidParams);
+        player_summaries(&client, &api_key, &[steam_id64])
+            .await?
+            .into_iter()
+            .next()
+            .ok_or(Error::AuthUserParseFail)
     }
+}
+
+/// Overridable to sign in against a local stand-in.
+fn openid_url() -> String {
+    env::var("STEAM_OPENID_URL").unwrap_or("https://steamcommunity.com/openid".to_string())
+}
+
+/// The app's own web api key, stored as its steam client secret, or the server's if it has
+/// one.
+pub async fn steam_api_key(state: &AppState, app_id: &str) -> Result<String> {
+    match app_credentials(state, app_id, "steam").await? {
+        Some(credentials) => Ok(credentials.client_secret),
+        None => env::var("STEAM_API_KEY").map_err(|_| Error::AuthProviderUnconfigured),
+    }
+}
 
-    let steam_id64 = query
-        .openid_claimed_id
-        .replace("https://steamcommunity.com/openid/id/", "");
+/// Looks up the profiles of up to 100 steam users at once.
+pub async fn player_summaries(
+    client: &reqwest::Client,
+    api_key: &str,
+    steam_ids: &[String],
+) -> Result<Vec<Account>> {
+    // overridable to read profiles from a local stand-in
+    let api_url = env::var("STEAM_API_URL").unwrap_or("https://api.steampowered.com".to_string());
 
     let steam_api_url = format!(
-        r"https://api.steampowered.com/ISteamUser/GetPlayerSummaries/v0002/?key={}&steamids={}",
-        env::var("STEAM_API_KEY").unwrap(),
-        steam_id64
+        r"{}/ISteamUser/GetPlayerSummaries/v0002/?key={}&steamids={}",
+        api_url,
+        api_key,
+        steam_ids.join(",")
     );
 
     let res: Value = client
@@ -140,49 +162,98 @@ async fn auth_redirect(
         .await
         .map_err(|_| Error::AuthUserParseFail)?;
 
-    let user = res
+    let players = res
         .get("response")
         .ok_or(Error::AuthMissingState)?
         .get("players")
         .ok_or(Error::AuthMissingState)?;
 
-    let user: SteamUser =
-        serde_json::from_value(user.get(0).ok_or(Error::AuthUserParseFail)?.to_owned())
-            .map_err(|_| Error::AuthUserParseFail)?;
+    let players: Vec<SteamUser> =
+        serde_json::from_value(players.to_owned()).map_err(|_| Error::AuthUserParseFail)?;
 
-    let user = Account {
-        id: Some(user.steamid),
-        avatar: Some(user.avatarhash),
-        username: Some(user.personaname),
-    };
+    Ok(players
+        .into_iter()
+        .map(|user| Account {
+            id: Some(user.steamid),
+            avatar: Some(user.avatarhash),
+            username: Some(user.personaname),
+        })
+        .collect())
+}
 
-    let (redirect_uri, app_id) = query.state.split_once(";").ok_or(Error::RedisGetFail)?;
+async fn auth_login(
+    Path(app_id): Path<String>,
+    Query(query): Query<LoginQuery>,
+    State(mut state): State<AppState>,
+) -> Result<Redirect> {
+    // steam sign in can involve steam guard, so it keeps the default five minutes
+    begin_login(&mut state, &Steam, app_id, query).await
+}
 
-    let code: String = thread_rng()
-        .sample_iter(&Alphanumeric)
-        .take(32)
-        .map(char::from)
-        .collect();
+async fn auth_redirect(
+    Query(callback): Query<Callback<AuthRequest>>,
+    State(mut state): State<AppState>,
+    cookies: Cookies,
+) -> Result<Redirect> {
+    finish_login(&mut state, &Steam, &cookies, callback).await
+}
 
-    let code_key = format!("{app_id}:code:{code}");
-    let ttl = 30;
+#[cfg(test)]
+mod tests {
+    use super::{AuthRequest, Steam};
+    use crate::api::auth::identity::IdentityProvider;
+    use crate::api::auth::stand_in::test_state;
+    use crate::db::app::create_app;
+    use crate::db::user::{create_user, get_user_by_id, Account};
 
-    let uuid = Uuid::from_str(&app_id).map_err(|_| Error::UuidFail)?;
+    #[tokio::test]
+    #[ignore = "needs TEST_POSTGRES_URL"]
+    async fn login_updates_profile() {
+        let state = test_state().await;
+        let app_id = create_app(&state.pg, "steam login".to_string())
+            .await
+            .unwrap();
 
-    match get_user(&state.pg, uuid, user.id.as_ref().map_or("", |s| s)).await? {
-        Some(_) => update_user_steam(&state.pg, uuid, &user).await?,
-        _ => create_user(&state.pg, uuid, None, Some(&user)).await?,
-    }
+        let old = Account {
+            id: Some("4001".to_string()),
+            avatar: Some("old".to_string()),
+            username: Some("old".to_string()),
+        };
+
+        let user_id = create_user(&state.pg, app_id, "steam", &old).await.unwrap();
 
-    state
-        .redis
-        .set(&code_key, &user.id)
-        .map_err(|_| Error::RedisSetFail)?;
+        let openid = |value: &str| value.to_string();
 
-    state
-        .redis
-        .expire(&code_key, ttl)
-        .map_err(|_| Error::RedisExpireFail)?;
+        let callback = AuthRequest {
+            openid_assoc_handle: openid("1234567890"),
+            openid_signed: openid(
+                "signed,op_endpoint,claimed_id,identity,return_to,response_nonce,assoc_handle",
+            ),
+            openid_sig: openid("signature"),
+            openid_ns: openid("http://specs.openid.net/auth/2.0"),
+            openid_op_endpoint: openid("https://steamcommunity.com/openid/login"),
+            openid_claimed_id: openid("https://steamcommunity.com/openid/id/4001"),
+            openid_identity: openid("https://steamcommunity.com/openid/id/4001"),
+            openid_return_to: openid("http://localhost/api/auth/steam/redirect"),
+            openid_response_nonce: openid("2024-01-01T00:00:00Zabc"),
+        };
 
-    Ok(Redirect::to(format!("{redirect_uri}?code={code}").as_ref()))
+        let account = Steam
+            .identify(&state, &app_id.to_string(), callback, "state")
+            .await
+            .unwrap();
+
+        assert_eq!(
+            Steam.save_user(&state.pg, app_id, &account).await.unwrap(),
+            user_id
+        );
+
+        let steam = get_user_by_id(&state.pg, app_id, user_id)
+            .await
+            .unwrap()
+            .unwrap()
+            .steam;
+        assert_eq!(steam.username.as_deref(), Some("player-4001"));
+        assert_eq!(steam.avatar.as_deref(), Some("hash-4001"));
+    }
 }
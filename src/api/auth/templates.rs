@@ -0,0 +1,40 @@
+use askama::Template;
+
+use crate::api::auth::upstream::ProviderChoice;
+
+#[derive(Template)]
+#[template(path = "device.html")]
+pub struct Device {
+    pub app_id: String,
+    pub app_name: String,
+    pub user_code: String,
+    pub providers: Vec<ProviderChoice>,
+    /// Set once the user signed in, asking them to approve the device.
+    pub confirm_token: Option<String>,
+}
+
+#[derive(Template)]
+#[template(path = "device_done.html")]
+pub struct DeviceDone {
+    pub approved: bool,
+}
+
+#[derive(Template)]
+#[template(path = "consent.html")]
+pub struct Consent {
+    pub app_id: String,
+    pub app_name: String,
+    pub id: String,
+    pub scopes: Vec<&'static str>,
+}
+
+#[derive(Template)]
+#[template(path = "chooser.html")]
+pub struct Chooser {
+    pub app_id: String,
+    pub app_name: String,
+    pub logo_url: Option<String>,
+    pub providers: Vec<ProviderChoice>,
+    /// The client's login parameters, passed on to the chosen provider.
+    pub query: String,
+}
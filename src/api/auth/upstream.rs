@@ -0,0 +1,394 @@
+use std::env;
+
+use axum::extract::{Path, Query, State};
+use axum::response::Redirect;
+use axum::routing::get;
+use axum::Router;
+use http::header::{ACCEPT, USER_AGENT};
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use oauth2::url::form_urlencoded::Serializer;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::Value;
+use sqlx::PgPool;
+use tower_cookies::Cookies;
+
+use crate::api::auth::identity::{
+    app_credentials, begin_login, finish_login, Callback, IdentityProvider, LoginQuery,
+};
+use crate::db::provider::{get_provider, get_providers, Provider, ProviderKind};
+use crate::db::user::Account;
+use crate::error::{Error, Result};
+use crate::state::AppState;
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/:slug/:app_id/", get(auth_login))
+        .route("/:slug/redirect", get(auth_redirect))
+}
+
+/// Providers with a hand-written login, everything else is looked up by slug.
+pub const BUILT_IN: [&str; 4] = ["discord", "steam", "google", "github"];
+
+/// Where the login for a built-in or registered provider starts, `None` for unknown ones.
+pub async fn login_path(pool: &PgPool, provider: &str, app_id: &str) -> Result<Option<String>> {
+    if BUILT_IN.contains(&provider) {
+        return Ok(Some(provider_path(provider, app_id)));
+    }
+
+    Ok(get_provider(pool, provider)
+        .await?
+        .map(|provider| provider_path(&provider.slug, app_id)))
+}
+
+fn provider_path(provider: &str, app_id: &str) -> String {
+    match BUILT_IN.contains(&provider) {
+        true => format!("/api/auth/{provider}/{app_id}/"),
+        false => format!("/api/auth/provider/{provider}/{app_id}/"),
+    }
+}
+
+/// A provider as offered on the login pages.
+pub struct ProviderChoice {
+    pub slug: String,
+    pub name: String,
+}
+
+impl ProviderChoice {
+    pub fn login_path(&self, app_id: &str) -> String {
+        provider_path(&self.slug, app_id)
+    }
+}
+
+/// The providers enabled for an app, the built-in ones first.
+pub async fn enabled_providers(pool: &PgPool, enabled: &[String]) -> Result<Vec<ProviderChoice>> {
+    let built_in = [
+        ("discord", "Discord"),
+        ("steam", "Steam"),
+        ("google", "Google"),
+        ("github", "GitHub"),
+    ]
+    .map(|(slug, name)| ProviderChoice {
+        slug: slug.to_string(),
+        name: name.to_string(),
+    });
+
+    let registered = get_providers(pool)
+        .await?
+        .into_iter()
+        .map(|provider| ProviderChoice {
+            slug: provider.slug,
+            name: provider.name,
+        });
+
+    Ok(built_in
+        .into_iter()
+        .chain(registered)
+        .filter(|provider| enabled.contains(&provider.slug))
+        .collect())
+}
+
+fn redirect_uri(slug: &str) -> String {
+    format!(
+        "{}/api/auth/provider/{slug}/redirect",
+        env::var("BASE_URL").unwrap()
+    )
+}
+
+/// The provider's endpoints, with those left unset read from its discovery document.
+struct Endpoints {
+    auth_url: String,
+    token_url: String,
+    userinfo_url: Option<String>,
+    jwks_url: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+struct Discovery {
+    authorization_endpoint: Option<String>,
+    token_endpoint: Option<String>,
+    userinfo_endpoint: Option<String>,
+    jwks_uri: Option<String>,
+}
+
+async fn endpoints(client: &reqwest::Client, provider: &Provider) -> Result<Endpoints> {
+    let complete = provider.auth_url.is_some()
+        && provider.token_url.is_some()
+        && match provider.kind {
+            ProviderKind::Oidc => provider.jwks_url.is_some(),
+            ProviderKind::OAuth2 => provider.userinfo_url.is_some(),
+        };
+
+    let discovery = match (&provider.issuer, complete) {
+        (Some(issuer), false) => client
+            .get(format!(
+                "{}/.well-known/openid-configuration",
+                issuer.trim_end_matches('/')
+            ))
+            .send()
+            .await
+            .map_err(|_| Error::AuthUserFetchFail)?
+            .json()
+            .await
+            .map_err(|_| Error::AuthUserParseFail)?,
+        _ => Discovery::default(),
+    };
+
+    Ok(Endpoints {
+        auth_url: provider
+            .auth_url
+            .clone()
+            .or(discovery.authorization_endpoint)
+            .ok_or(Error::AuthInvalidParams)?,
+        token_url: provider
+            .token_url
+            .clone()
+            .or(discovery.token_endpoint)
+            .ok_or(Error::AuthInvalidParams)?,
+        userinfo_url: provider
+            .userinfo_url
+            .clone()
+            .or(discovery.userinfo_endpoint),
+        jwks_url: provider.jwks_url.clone().or(discovery.jwks_uri),
+    })
+}
+
+#[derive(Deserialize)]
+struct AuthRequest {
+    code: String,
+}
+
+#[derive(Deserialize)]
+struct UpstreamTokens {
+    access_token: String,
+    id_token: Option<String>,
+}
+
+/// A provider registered from the dashboard, its slug naming it in login state keys.
+struct Upstream(Provider);
+
+impl Upstream {
+    /// The client id and secret to sign in with, the app's own when it registered them.
+    async fn credentials(&self, state: &AppState, app_id: &str) -> Result<(String, String)> {
+        Ok(match app_credentials(state, app_id, &self.0.slug).await? {
+            Some(credentials) => (
+                credentials.client_id.ok_or(Error::AuthInvalidParams)?,
+                credentials.client_secret,
+            ),
+            None => (self.0.client_id.clone(), self.0.client_secret.clone()),
+        })
+    }
+}
+
+impl IdentityProvider for Upstream {
+    type Callback = AuthRequest;
+
+    fn name(&self) -> &str {
+        &self.0.slug
+    }
+
+    async fn authorize_url(&self, state: &AppState, app_id: &str, key: &str) -> Result<String> {
+        let provider = &self.0;
+        let endpoints = endpoints(&reqwest::Client::new(), provider).await?;
+        let (client_id, _) = self.credentials(state, app_id).await?;
+
+        let mut auth_query = Serializer::new(String::new());
+
+        auth_query.append_pair("response_type", "code");
+        auth_query.append_pair("client_id", &client_id);
+        auth_query.append_pair("redirect_uri", &redirect_uri(&provider.slug));
+        auth_query.append_pair("state", key);
+
+        if !provider.scopes.is_empty() {
+            auth_query.append_pair("scope", &provider.scopes);
+        }
+
+        // the state doubles as the ID token nonce for OIDC providers
+        if provider.kind == ProviderKind::Oidc {
+            auth_query.append_pair("nonce", key);
+        }
+
+        let separator = match endpoints.auth_url.contains('?') {
+            true => '&',
+            false => '?',
+        };
+
+        Ok(format!(
+            "{}{separator}{}",
+            endpoints.auth_url,
+            auth_query.finish()
+        ))
+    }
+
+    async fn identify(
+        &self,
+        state: &AppState,
+        app_id: &str,
+        callback: AuthRequest,
+        key: &str,
+    ) -> Result<Account> {
+        let provider = &self.0;
+        let (client_id, client_secret) = self.credentials(state, app_id).await?;
+
+        let client = reqwest::Client::new();
+        let endpoints = endpoints(&client, provider).await?;
+
+        let redirect_uri = redirect_uri(&provider.slug);
+
+        let tokens: UpstreamTokens = client
+            .post(&endpoints.token_url)
+            .header(ACCEPT, "application/json")
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", &callback.code),
+                ("client_id", &client_id),
+                ("client_secret", &client_secret),
+                ("redirect_uri", &redirect_uri),
+            ])
+            .send()
+            .await
+            .map_err(|_| Error::AuthTokenExchangeFail)?
+            .json()
+            .await
+            .map_err(|_| Error::AuthTokenExchangeFail)?;
+
+        let mut claims = match provider.kind {
+            ProviderKind::Oidc => {
+                let id_token = tokens.id_token.as_ref().ok_or(Error::JwtInvalidToken)?;
+
+                let claims: Value = verify_id_token(
+                    &client,
+                    endpoints.jwks_url.as_ref().ok_or(Error::JwtInvalidToken)?,
+                    provider.issuer.as_ref().ok_or(Error::JwtInvalidToken)?,
+                    &client_id,
+                    id_token,
+                )
+                .await?;
+
+                if claims.get("nonce").and_then(Value::as_str) != Some(key) {
+                    return Err(Error::JwtInvalidToken);
+                }
+
+                claims
+            }
+            ProviderKind::OAuth2 => Value::Object(Default::default()),
+        };
+
+        // ID tokens often leave out profile claims, so fill them in from userinfo
+        if let Some(userinfo_url) = &endpoints.userinfo_url {
+            let userinfo = fetch_userinfo(&client, userinfo_url, &tokens.access_token).await;
+
+            match (userinfo, &mut claims) {
+                (Ok(Value::Object(userinfo)), Value::Object(claims)) => {
+                    for (name, value) in userinfo {
+                        claims.entry(name).or_insert(value);
+                    }
+                }
+                (Err(e), _) if provider.kind == ProviderKind::OAuth2 => return Err(e),
+                _ => {}
+            }
+        }
+
+        Ok(Account {
+            id: Some(claim(&claims, &provider.id_claim).ok_or(Error::AuthUserParseFail)?),
+            avatar: claim(&claims, &provider.avatar_claim),
+            username: claim(&claims, &provider.username_claim),
+        })
+    }
+}
+
+async fn upstream(pool: &PgPool, slug: &str) -> Result<Upstream> {
+    get_provider(pool, slug)
+        .await?
+        .map(Upstream)
+        .ok_or(Error::AuthInvalidParams)
+}
+
+async fn auth_login(
+    Path((slug, app_id)): Path<(String, String)>,
+    Query(query): Query<LoginQuery>,
+    State(mut state): State<AppState>,
+) -> Result<Redirect> {
+    let provider = upstream(&state.pg, &slug).await?;
+
+    begin_login(&mut state, &provider, app_id, query).await
+}
+
+async fn auth_redirect(
+    Path(slug): Path<String>,
+    Query(callback): Query<Callback<AuthRequest>>,
+    State(mut state): State<AppState>,
+    cookies: Cookies,
+) -> Result<Redirect> {
+    let provider = upstream(&state.pg, &slug).await?;
+
+    finish_login(&mut state, &provider, &cookies, callback).await
+}
+
+async fn fetch_userinfo(
+    client: &reqwest::Client,
+    userinfo_url: &str,
+    access_token: &str,
+) -> Result<Value> {
+    client
+        .get(userinfo_url)
+        .bearer_auth(access_token)
+        .header(ACCEPT, "application/json")
+        // some APIs, GitHub's among them, reject requests without one
+        .header(USER_AGENT, "sso-server")
+        .send()
+        .await
+        .map_err(|_| Error::AuthUserFetchFail)?
+        .json()
+        .await
+        .map_err(|_| Error::AuthUserParseFail)
+}
+
+/// Reads a claim by its dot separated path, so nested claims like `data.id` can be mapped.
+fn claim(claims: &Value, path: &str) -> Option<String> {
+    let value = path
+        .split('.')
+        .try_fold(claims, |value, name| value.get(name))?;
+
+    match value {
+        Value::String(value) => Some(value.clone()),
+        Value::Number(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+/// Checks an upstream ID token's signature against the provider's published keys, and its
+/// issuer, audience and expiry.
+pub async fn verify_id_token<T: DeserializeOwned>(
+    client: &reqwest::Client,
+    jwks_url: &str,
+    issuer: &str,
+    client_id: &str,
+    id_token: &str,
+) -> Result<T> {
+    let kid = decode_header(id_token)
+        .map_err(|_| Error::JwtInvalidToken)?
+        .kid
+        .ok_or(Error::JwtInvalidToken)?;
+
+    let jwks: JwkSet = client
+        .get(jwks_url)
+        .send()
+        .await
+        .map_err(|_| Error::AuthUserFetchFail)?
+        .json()
+        .await
+        .map_err(|_| Error::AuthUserParseFail)?;
+
+    let jwk = jwks.find(&kid).ok_or(Error::JwtInvalidToken)?;
+    let decoding_key = DecodingKey::from_jwk(jwk).map_err(|_| Error::JwtDecodeGenFail)?;
+
+    let mut validation = Validation::new(Algorithm::RS256);
+    validation.set_audience(&[client_id]);
+    validation.set_issuer(&[issuer]);
+
+    decode::<T>(id_token, &decoding_key, &validation)
+        .map(|token| token.claims)
+        .map_err(|_| Error::JwtInvalidToken)
+}
@@ -8,28 +8,38 @@ use axum::{
     Form, Router,
 };
 use http::{HeaderMap, Request};
-use redis::Commands;
 use serde::Deserialize;
 use sqlx::types::Uuid;
-use tower_cookies::{
-    cookie::{time::Duration, SameSite},
-    Cookie, Cookies,
-};
+use tower_cookies::Cookies;
 
 use crate::{
+    api::auth::{
+        consent::SCOPES, issue_tokens, merge::merge_accounts, redeem_code, rotate_tokens,
+        set_token_cookies, upstream::BUILT_IN, verify_access_token,
+    },
     db::{
         app::{
-            add_redirect_uri, create_app, delete_redirect_uri, get_app, get_apps, get_private_key,
-            get_public_key, get_redirect_uris, remove_app, update_redirect_uri,
+            add_redirect_uri, create_app, delete_redirect_uri, get_app, get_apps,
+            get_redirect_uris, remove_app, rotate_client_secret, set_logo_url, set_providers,
+            set_require_pkce, set_scopes, set_token_delivery, update_redirect_uri,
+            update_session_policy, SessionPolicy, TokenDelivery,
+        },
+        discord::{add_discord_rule, get_discord_rules, remove_discord_rule, set_discord_scopes},
+        key::{get_keys, get_public_keys, retire_key, rotate_key},
+        provider::{
+            create_provider, get_app_credential_names, get_providers, remove_app_credentials,
+            remove_provider, set_app_credentials, NewProvider, ProviderKind,
         },
-        user::get_user,
     },
     error::{Error, Result},
-    jwt::{gen_access_token, gen_refresh_token, verify_token},
+    jwt::{key_id, verify_token},
     state::AppState,
 };
 
-use self::templates::{App, AppId, CreateNewApp, Home, Login, Uri};
+use self::templates::{
+    App, AppId, ClientSecret, CreateNewApp, Credentials, DiscordRules, Home, KeyView, Keys, Login,
+    ProviderView, Providers, ScopeView, Uri,
+};
 
 pub mod templates;
 
@@ -41,8 +51,32 @@ pub fn routes(state: AppState) -> Router<AppState> {
         .route("/app/:app_id/uri", put(add_uri))
         .route("/app/:app_id/uri", patch(patch_uri))
         .route("/app/:app_id/uri", delete(delete_uri))
+        .route("/app/:app_id/pkce", patch(patch_pkce))
+        .route("/app/:app_id/session", patch(patch_session))
+        .route("/app/:app_id/scopes", patch(patch_scopes))
+        .route("/app/:app_id/delivery", patch(patch_delivery))
+        .route("/app/:app_id/providers", patch(patch_providers))
+        .route("/app/:app_id/logo", patch(patch_logo))
+        .route("/app/:app_id/discord/scopes", patch(patch_discord_scopes))
+        .route("/app/:app_id/discord/rules", put(put_discord_rule))
+        .route(
+            "/app/:app_id/discord/rules/:rule_id",
+            delete(delete_discord_rule),
+        )
+        .route("/app/:app_id/secret", post(rotate_secret))
+        .route("/app/:app_id/credentials", put(put_credentials))
+        .route(
+            "/app/:app_id/credentials/:provider",
+            delete(delete_credentials),
+        )
+        .route("/app/:app_id/users/merge", post(merge_app_users))
+        .route("/app/:app_id/keys/rotate", post(rotate_app_key))
+        .route("/app/:app_id/keys/:key_id/retire", post(retire_app_key))
         .route("/app/new", get(new_app_page))
         .route("/app/new", post(create_new_app))
+        .route("/providers", get(providers_page))
+        .route("/providers", post(create_new_provider))
+        .route("/providers/:provider_id", delete(delete_provider))
         .route_layer(middleware::from_fn_with_state(state, guard))
         .route("/login", get(login_page))
         .route("/login_redir", get(login_redir))
@@ -62,7 +96,7 @@ pub async fn guard<T>(
     let app_id = env::var("MAIN_APP_ID").unwrap();
     let uuid = Uuid::from_str(&app_id).map_err(|_| Error::UuidFail)?;
 
-    let public = get_public_key(&state.pg, uuid).await?;
+    let public_keys = get_public_keys(&state.pg, uuid).await?;
 
     if access_token.is_none() && refresh_token.is_some() {
         let refresh_token = refresh_token
@@ -70,72 +104,21 @@ pub async fn guard<T>(
             .ok_or(Error::AuthMissingCookie)?
             .value();
 
-        let public_key = get_public_key(&state.pg, uuid).await?;
-
-        let user = verify_token(refresh_token, public_key.as_bytes())?;
+        let user = verify_token(refresh_token, &app_id, &public_keys)?;
 
         if user.steam.id != Some(steam_id) {
             return Err(Error::AuthMissingCookie);
         }
 
-        let user_id = user.user_id;
-        let token_key = format!("{app_id}:{user_id}");
-
-        let token_valid: Option<String> = state
-            .redis
-            .zscore(&token_key, refresh_token)
-            .map_err(|_| Error::RedisGetFail)?;
-
-        token_valid.ok_or(Error::RedisGetEmpty)?;
-
-        state
-            .redis
-            .zrem(&token_key, refresh_token)
-            .map_err(|_| Error::RedisDelFail)?;
-
-        let private_key = get_private_key(&state.pg, uuid).await?;
-
-        let refresh_token = gen_refresh_token(&user, &app_id, private_key.as_bytes())?;
-        let access_token = gen_access_token(&user, &app_id, private_key.as_bytes())?;
-
-        state
-            .redis
-            .zadd(&token_key, &refresh_token, 1)
-            .map_err(|_| Error::RedisSetFail)?;
-
-        state
-            .redis
-            .expire(&refresh_token, 60 * 60 * 24 * 3)
-            .map_err(|_| Error::RedisExpireFail)?;
-
-        let mut refresh_cookie = Cookie::build("refresh", refresh_token)
-            .path("/")
-            .same_site(SameSite::Lax)
-            .max_age(Duration::seconds(60 * 60 * 24 * 3))
-            .http_only(true);
-
-        let mut access_cookie = Cookie::build("access", access_token)
-            .path("/")
-            .same_site(SameSite::Lax)
-            .max_age(Duration::seconds(60 * 5))
-            .http_only(false);
-
-        if env::var("DEV").is_err() {
-            let domain = env::var("BASE_DOMAIN").unwrap();
-
-            refresh_cookie = refresh_cookie.domain(domain.clone()).secure(true);
-
-            access_cookie = access_cookie.domain(domain).secure(true);
-        }
-
-        let refresh_cookie = refresh_cookie.finish();
-        let access_cookie = access_cookie.finish();
+        let tokens = rotate_tokens(&mut state, &app_id, refresh_token).await?;
 
-        cookies.add(refresh_cookie);
-        cookies.add(access_cookie);
+        set_token_cookies(&cookies, &tokens, false);
     } else if access_token.is_some() {
         let token = access_token.as_ref().ok_or(Error::AuthMissingCookie)?;
-        let user = verify_token(token.value(), public.as_bytes())?;
+        let user = verify_access_token(&mut state, &app_id, token.value())
+            .await?
+            .user
+            .ok_or(Error::JwtInvalidToken)?;
 
         if user.steam.id != Some(steam_id) {
             return Err(Error::AuthMissingCookie);
@@ -184,12 +167,424 @@ async fn create_new_app(
 async fn app_page(State(state): State<AppState>, Path(app_id): Path<String>) -> Result<App> {
     let uuid = Uuid::from_str(&app_id).map_err(|_| Error::UuidFail)?;
 
+    let app = get_app(&state.pg, uuid).await?;
+
+    let scopes = SCOPES
+        .iter()
+        .map(|(name, description)| ScopeView {
+            name,
+            description,
+            enabled: app.scopes.iter().any(|scope| scope == name),
+        })
+        .collect();
+
+    let discord_scopes = DISCORD_SCOPES
+        .iter()
+        .map(|(name, description)| ScopeView {
+            name,
+            description,
+            enabled: app.discord_scopes.iter().any(|scope| scope == name),
+        })
+        .collect();
+
+    let credentials = credential_list(&state, app_id.clone()).await?;
+
+    let login_providers = credentials
+        .providers
+        .iter()
+        .map(|name| ProviderView {
+            name: name.clone(),
+            enabled: app.providers.contains(name),
+        })
+        .collect();
+
     Ok(App {
-        app: get_app(&state.pg, uuid).await?,
+        app,
         redirect_uris: get_redirect_uris(&state.pg, uuid).await?,
+        keys: key_list(&state, app_id).await?.keys,
+        scopes,
+        credentials: credentials.credentials,
+        providers: credentials.providers,
+        login_providers,
+        discord_scopes,
+        rules: get_discord_rules(&state.pg, uuid).await?,
+    })
+}
+
+#[derive(Deserialize)]
+struct PatchPkceReq {
+    require_pkce: Option<String>,
+}
+
+async fn patch_pkce(
+    State(state): State<AppState>,
+    Path(app_id): Path<String>,
+    Form(body): Form<PatchPkceReq>,
+) -> Result<()> {
+    let uuid = Uuid::from_str(&app_id).map_err(|_| Error::UuidFail)?;
+
+    // the dashboard signs in to the main app without a code challenge
+    let main_app_id =
+        Uuid::from_str(&env::var("MAIN_APP_ID").unwrap()).map_err(|_| Error::UuidFail)?;
+
+    if body.require_pkce.is_some() && uuid == main_app_id {
+        return Err(Error::AuthInvalidParams);
+    }
+
+    set_require_pkce(&state.pg, uuid, body.require_pkce.is_some()).await?;
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct PatchSessionReq {
+    access_ttl: i32,
+    refresh_ttl: i32,
+    session_ttl: String,
+    idle_timeout: String,
+    refresh_grace: i32,
+}
+
+async fn patch_session(
+    State(state): State<AppState>,
+    Path(app_id): Path<String>,
+    Form(body): Form<PatchSessionReq>,
+) -> Result<()> {
+    let uuid = Uuid::from_str(&app_id).map_err(|_| Error::UuidFail)?;
+
+    // left empty, the optional limits are turned off
+    let optional = |value: &str| match value.trim() {
+        "" => Ok(None),
+        value => value
+            .parse()
+            .map(Some)
+            .map_err(|_| Error::AuthInvalidParams),
+    };
+
+    let policy = SessionPolicy {
+        access_ttl: body.access_ttl,
+        refresh_ttl: body.refresh_ttl,
+        session_ttl: optional(&body.session_ttl)?,
+        idle_timeout: optional(&body.idle_timeout)?,
+        refresh_grace: body.refresh_grace,
+    };
+
+    let limits = [
+        Some(policy.access_ttl),
+        Some(policy.refresh_ttl),
+        policy.session_ttl,
+        policy.idle_timeout,
+    ];
+
+    if limits.iter().flatten().any(|seconds| *seconds <= 0) || policy.refresh_grace < 0 {
+        return Err(Error::AuthInvalidParams);
+    }
+
+    update_session_policy(&state.pg, uuid, &policy).await
+}
+
+#[derive(Deserialize)]
+struct PatchDeliveryReq {
+    token_delivery: TokenDelivery,
+}
+
+async fn patch_delivery(
+    State(state): State<AppState>,
+    Path(app_id): Path<String>,
+    Form(body): Form<PatchDeliveryReq>,
+) -> Result<()> {
+    let uuid = Uuid::from_str(&app_id).map_err(|_| Error::UuidFail)?;
+
+    set_token_delivery(&state.pg, uuid, body.token_delivery).await
+}
+
+#[derive(Deserialize)]
+struct PatchScopesReq {
+    profile: Option<String>,
+    discord: Option<String>,
+    steam: Option<String>,
+    google: Option<String>,
+    github: Option<String>,
+    admin: Option<String>,
+}
+
+async fn patch_scopes(
+    State(state): State<AppState>,
+    Path(app_id): Path<String>,
+    Form(body): Form<PatchScopesReq>,
+) -> Result<()> {
+    let uuid = Uuid::from_str(&app_id).map_err(|_| Error::UuidFail)?;
+
+    let checked = [
+        ("profile", body.profile),
+        ("discord", body.discord),
+        ("steam", body.steam),
+        ("google", body.google),
+        ("github", body.github),
+        ("admin", body.admin),
+    ];
+
+    let scopes: Vec<String> = checked
+        .into_iter()
+        .filter(|(_, value)| value.is_some())
+        .map(|(scope, _)| scope.to_string())
+        .collect();
+
+    set_scopes(&state.pg, uuid, &scopes).await
+}
+
+/// The built-in providers and the registered ones, by the name logins know them by.
+async fn provider_names(state: &AppState) -> Result<Vec<String>> {
+    let registered = get_providers(&state.pg).await?;
+
+    Ok(BUILT_IN
+        .iter()
+        .map(|provider| provider.to_string())
+        .chain(registered.into_iter().map(|provider| provider.slug))
+        .collect())
+}
+
+async fn credential_list(state: &AppState, app_id: String) -> Result<Credentials> {
+    let uuid = Uuid::from_str(&app_id).map_err(|_| Error::UuidFail)?;
+
+    Ok(Credentials {
+        credentials: get_app_credential_names(&state.pg, uuid).await?,
+        providers: provider_names(state).await?,
+        app: AppId { id: app_id },
     })
 }
 
+#[derive(Deserialize)]
+struct PutCredentialsReq {
+    provider: String,
+    client_id: String,
+    client_secret: String,
+}
+
+async fn put_credentials(
+    State(state): State<AppState>,
+    Path(app_id): Path<String>,
+    Form(body): Form<PutCredentialsReq>,
+) -> Result<Credentials> {
+    let uuid = Uuid::from_str(&app_id).map_err(|_| Error::UuidFail)?;
+
+    // steam only takes an api key, everything else signs in with a client id
+    let client_id = match body.client_id.trim() {
+        "" if body.provider == "steam" => None,
+        "" => return Err(Error::AuthInvalidParams),
+        client_id => Some(client_id),
+    };
+
+    if !provider_names(&state).await?.contains(&body.provider) || body.client_secret.is_empty() {
+        return Err(Error::AuthInvalidParams);
+    }
+
+    set_app_credentials(
+        &state.pg,
+        uuid,
+        &body.provider,
+        client_id,
+        &body.client_secret,
+    )
+    .await?;
+
+    credential_list(&state, app_id).await
+}
+
+async fn delete_credentials(
+    State(state): State<AppState>,
+    Path((app_id, provider)): Path<(String, String)>,
+) -> Result<Credentials> {
+    let uuid = Uuid::from_str(&app_id).map_err(|_| Error::UuidFail)?;
+
+    remove_app_credentials(&state.pg, uuid, &provider).await?;
+
+    credential_list(&state, app_id).await
+}
+
+/// Takes the checked `provider` boxes, one pair each, as the form repeats the name.
+async fn patch_providers(
+    State(state): State<AppState>,
+    Path(app_id): Path<String>,
+    Form(body): Form<Vec<(String, String)>>,
+) -> Result<()> {
+    let uuid = Uuid::from_str(&app_id).map_err(|_| Error::UuidFail)?;
+
+    let known = provider_names(&state).await?;
+
+    let providers: Vec<String> = body
+        .into_iter()
+        .filter(|(key, value)| key == "provider" && known.contains(value))
+        .map(|(_, value)| value)
+        .collect();
+
+    set_providers(&state.pg, uuid, &providers).await
+}
+
+#[derive(Deserialize)]
+struct PatchLogoReq {
+    logo_url: String,
+}
+
+async fn patch_logo(
+    State(state): State<AppState>,
+    Path(app_id): Path<String>,
+    Form(body): Form<PatchLogoReq>,
+) -> Result<()> {
+    let uuid = Uuid::from_str(&app_id).map_err(|_| Error::UuidFail)?;
+
+    let logo_url = match body.logo_url.trim() {
+        "" => None,
+        logo_url => Some(logo_url),
+    };
+
+    set_logo_url(&state.pg, uuid, logo_url).await
+}
+
+/// Discord scopes an app can request on top of `identify`, with what they're for.
+const DISCORD_SCOPES: [(&str, &str); 2] = [
+    ("guilds", "which guilds the user is in"),
+    (
+        "guilds.members.read",
+        "the user's roles in the rules' guilds",
+    ),
+];
+
+async fn patch_discord_scopes(
+    State(state): State<AppState>,
+    Path(app_id): Path<String>,
+    Form(body): Form<Vec<(String, String)>>,
+) -> Result<()> {
+    let uuid = Uuid::from_str(&app_id).map_err(|_| Error::UuidFail)?;
+
+    let scopes: Vec<String> = DISCORD_SCOPES
+        .iter()
+        .filter(|(name, _)| body.iter().any(|(key, _)| key == name))
+        .map(|(name, _)| name.to_string())
+        .collect();
+
+    set_discord_scopes(&state.pg, uuid, &scopes).await
+}
+
+async fn discord_rule_list(state: &AppState, app_id: String) -> Result<DiscordRules> {
+    let uuid = Uuid::from_str(&app_id).map_err(|_| Error::UuidFail)?;
+
+    Ok(DiscordRules {
+        rules: get_discord_rules(&state.pg, uuid).await?,
+        app: AppId { id: app_id },
+    })
+}
+
+#[derive(Deserialize)]
+struct PutDiscordRuleReq {
+    guild_id: String,
+    role_id: String,
+}
+
+async fn put_discord_rule(
+    State(state): State<AppState>,
+    Path(app_id): Path<String>,
+    Form(body): Form<PutDiscordRuleReq>,
+) -> Result<DiscordRules> {
+    let uuid = Uuid::from_str(&app_id).map_err(|_| Error::UuidFail)?;
+
+    // discord ids are snowflakes, numbers sent as strings
+    let snowflake = |id: &str| !id.is_empty() && id.chars().all(|c| c.is_ascii_digit());
+
+    let guild_id = body.guild_id.trim();
+
+    let role_id = match body.role_id.trim() {
+        "" => None,
+        role_id => Some(role_id),
+    };
+
+    if !snowflake(guild_id) || role_id.is_some_and(|role_id| !snowflake(role_id)) {
+        return Err(Error::AuthInvalidParams);
+    }
+
+    add_discord_rule(&state.pg, uuid, guild_id, role_id).await?;
+
+    discord_rule_list(&state, app_id).await
+}
+
+async fn delete_discord_rule(
+    State(state): State<AppState>,
+    Path((app_id, rule_id)): Path<(String, i32)>,
+) -> Result<DiscordRules> {
+    let uuid = Uuid::from_str(&app_id).map_err(|_| Error::UuidFail)?;
+
+    remove_discord_rule(&state.pg, uuid, rule_id).await?;
+
+    discord_rule_list(&state, app_id).await
+}
+
+#[derive(Deserialize)]
+struct MergeUsersReq {
+    primary: i32,
+    duplicate: i32,
+}
+
+async fn merge_app_users(
+    State(mut state): State<AppState>,
+    Path(app_id): Path<String>,
+    Form(body): Form<MergeUsersReq>,
+) -> Result<()> {
+    merge_accounts(&mut state, &app_id, body.primary, body.duplicate).await
+}
+
+async fn rotate_secret(
+    State(state): State<AppState>,
+    Path(app_id): Path<String>,
+) -> Result<ClientSecret> {
+    let uuid = Uuid::from_str(&app_id).map_err(|_| Error::UuidFail)?;
+
+    Ok(ClientSecret {
+        secret: rotate_client_secret(&state.pg, uuid).await?,
+    })
+}
+
+async fn key_list(state: &AppState, app_id: String) -> Result<Keys> {
+    let uuid = Uuid::from_str(&app_id).map_err(|_| Error::UuidFail)?;
+
+    let keys = get_keys(&state.pg, uuid)
+        .await?
+        .into_iter()
+        .map(|key| {
+            Ok(KeyView {
+                kid: key_id(key.public_key.as_bytes())?,
+                id: key.id,
+                status: key.status,
+                created_at: key.created_at,
+                public_key: key.public_key,
+            })
+        })
+        .collect::<Result<_>>()?;
+
+    Ok(Keys {
+        app: AppId { id: app_id },
+        keys,
+    })
+}
+
+async fn rotate_app_key(State(state): State<AppState>, Path(app_id): Path<String>) -> Result<Keys> {
+    let uuid = Uuid::from_str(&app_id).map_err(|_| Error::UuidFail)?;
+
+    rotate_key(&state.pg, uuid).await?;
+
+    key_list(&state, app_id).await
+}
+
+async fn retire_app_key(
+    State(state): State<AppState>,
+    Path((app_id, key_id)): Path<(String, i32)>,
+) -> Result<Keys> {
+    let uuid = Uuid::from_str(&app_id).map_err(|_| Error::UuidFail)?;
+
+    retire_key(&state.pg, uuid, key_id).await?;
+
+    key_list(&state, app_id).await
+}
+
 async fn login_page() -> Login {
     let app_id = env::var("MAIN_APP_ID").unwrap();
 
@@ -264,75 +659,75 @@ async fn delete_app(
     Ok(headers.into_response())
 }
 
-#[derive(Deserialize)]
-struct LoginRedir {
-    code: String,
+async fn providers_page(State(state): State<AppState>) -> Result<Providers> {
+    Ok(Providers {
+        providers: get_providers(&state.pg).await?,
+        base_url: env::var("BASE_URL").unwrap(),
+    })
 }
 
-async fn login_redir(
-    cookies: Cookies,
-    State(mut state): State<AppState>,
-    Query(query): Query<LoginRedir>,
-) -> Result<Redirect> {
-    let app_id = env::var("MAIN_APP_ID").unwrap();
-
-    let key = format!("{}:code:{}", app_id, query.code);
-
-    let user_id: Option<String> = state.redis.get(&key).map_err(|_| Error::RedisGetFail)?;
-    let user_id = user_id.ok_or(Error::RedisGetEmpty)?;
-
-    state.redis.del(&key).map_err(|_| Error::RedisDelFail)?;
-
-    let uuid = Uuid::from_str(&app_id).map_err(|_| Error::UuidFail)?;
+async fn create_new_provider(
+    State(state): State<AppState>,
+    Form(body): Form<NewProvider>,
+) -> Result<impl IntoResponse> {
+    let slug = body.slug.trim();
 
-    let user = get_user(&state.pg, uuid, &user_id)
-        .await?
-        .ok_or(Error::PgNone)?;
+    // the slug ends up in login urls and the authorize endpoint's provider parameter
+    let valid_slug = !slug.is_empty()
+        && !BUILT_IN.contains(&slug)
+        && slug
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
 
-    let private_key = get_private_key(&state.pg, uuid).await?;
+    let filled = |value: &str| !value.trim().is_empty();
 
-    let access_token = gen_access_token(&user, &app_id, private_key.as_bytes())?;
-    let refresh_token = gen_refresh_token(&user, &app_id, private_key.as_bytes())?;
+    let endpoints = match body.kind {
+        ProviderKind::Oidc => filled(&body.issuer),
+        ProviderKind::OAuth2 => {
+            filled(&body.auth_url) && filled(&body.token_url) && filled(&body.userinfo_url)
+        }
+    };
 
-    let user_id = user.user_id;
+    if !valid_slug || !endpoints || !filled(&body.client_id) || !filled(&body.id_claim) {
+        return Err(Error::AuthInvalidParams);
+    }
 
-    let token_key = format!("{app_id}:{user_id}");
+    create_provider(&state.pg, &body).await?;
 
-    state
-        .redis
-        .zadd(&token_key, &refresh_token, 1)
-        .map_err(|_| Error::RedisSetFail)?;
+    let mut headers = HeaderMap::new();
 
-    state
-        .redis
-        .expire(&refresh_token, 60 * 60 * 24 * 3)
-        .map_err(|_| Error::RedisExpireFail)?;
+    headers.insert("HX-Location", "/dashboard/providers".parse().unwrap());
 
-    let mut refresh_cookie = Cookie::build("refresh", refresh_token)
-        .path("/")
-        .same_site(SameSite::Lax)
-        .max_age(Duration::seconds(60 * 60 * 24 * 3))
-        .http_only(true);
+    Ok((
+        headers,
+        Redirect::to("/dashboard/providers").into_response(),
+    ))
+}
 
-    let mut access_cookie = Cookie::build("access", access_token)
-        .path("/")
-        .same_site(SameSite::Lax)
-        .max_age(Duration::seconds(60 * 5))
-        .http_only(true);
+async fn delete_provider(
+    State(state): State<AppState>,
+    Path(provider_id): Path<i32>,
+) -> Result<()> {
+    remove_provider(&state.pg, provider_id).await
+}
 
-    if env::var("DEV").is_err() {
-        let domain = env::var("BASE_DOMAIN").unwrap();
+#[derive(Deserialize)]
+struct LoginRedir {
+    code: String,
+}
 
-        refresh_cookie = refresh_cookie.domain(domain.clone()).secure(true);
+async fn login_redir(
+    cookies: Cookies,
+    State(mut state): State<AppState>,
+    Query(query): Query<LoginRedir>,
+) -> Result<Redirect> {
+    let app_id = env::var("MAIN_APP_ID").unwrap();
 
-        access_cookie = access_cookie.domain(domain).secure(true);
-    }
+    let (user, auth_code) = redeem_code(&mut state, &app_id, &query.code, None, None).await?;
 
-    let refresh_cookie = refresh_cookie.finish();
-    let access_cookie = access_cookie.finish();
+    let tokens = issue_tokens(&mut state, &app_id, &user, auth_code.scope.as_deref()).await?;
 
-    cookies.add(refresh_cookie);
-    cookies.add(access_cookie);
+    set_token_cookies(&cookies, &tokens, true);
 
     Ok(Redirect::to("/dashboard"))
 }
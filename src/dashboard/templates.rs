@@ -1,6 +1,11 @@
 use askama::Template;
 
-use crate::db::app::{AppDB, AppNames, RedirectUri};
+use crate::db::{
+    app::{AppDB, AppNames, RedirectUri, TokenDelivery},
+    discord::DiscordRule,
+    key::KeyStatus,
+    provider::{AppCredentialNames, ProviderNames},
+};
 
 #[derive(Template)]
 #[template(path = "index.html")]
@@ -17,6 +22,26 @@ pub struct CreateNewApp {}
 pub struct App {
     pub app: AppDB,
     pub redirect_uris: Vec<RedirectUri>,
+    pub keys: Vec<KeyView>,
+    pub scopes: Vec<ScopeView>,
+    pub credentials: Vec<AppCredentialNames>,
+    pub providers: Vec<String>,
+    pub login_providers: Vec<ProviderView>,
+    pub discord_scopes: Vec<ScopeView>,
+    pub rules: Vec<DiscordRule>,
+}
+
+#[derive(Debug)]
+pub struct ProviderView {
+    pub name: String,
+    pub enabled: bool,
+}
+
+#[derive(Debug)]
+pub struct ScopeView {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub enabled: bool,
 }
 
 #[derive(Template)]
@@ -35,3 +60,47 @@ pub struct Uri {
     pub app: AppId,
     pub redirect: RedirectUri,
 }
+
+#[derive(Debug)]
+pub struct KeyView {
+    pub id: i32,
+    pub kid: String,
+    pub status: KeyStatus,
+    pub created_at: String,
+    pub public_key: String,
+}
+
+#[derive(Template)]
+#[template(path = "keys.html")]
+pub struct Keys {
+    pub app: AppId,
+    pub keys: Vec<KeyView>,
+}
+
+#[derive(Template)]
+#[template(path = "credentials.html")]
+pub struct Credentials {
+    pub app: AppId,
+    pub credentials: Vec<AppCredentialNames>,
+    pub providers: Vec<String>,
+}
+
+#[derive(Template)]
+#[template(path = "discord_rules.html")]
+pub struct DiscordRules {
+    pub app: AppId,
+    pub rules: Vec<DiscordRule>,
+}
+
+#[derive(Template)]
+#[template(path = "providers.html")]
+pub struct Providers {
+    pub providers: Vec<ProviderNames>,
+    pub base_url: String,
+}
+
+#[derive(Template)]
+#[template(path = "secret.html")]
+pub struct ClientSecret {
+    pub secret: String,
+}
@@ -1,8 +1,9 @@
-use std::env;
-
-use openssl::rsa::Rsa;
-use sqlx::{types::Uuid, FromRow, PgPool, Row};
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use serde::Deserialize;
+use sqlx::{types::Uuid, FromRow, PgPool, Row, Type};
 
+use crate::db::key::insert_key;
 use crate::error::{Error, Result};
 
 pub async fn validate_redirect_uri(pool: &PgPool, app_id: Uuid, uri: &str) -> Result<String> {
@@ -18,62 +19,27 @@ pub async fn validate_redirect_uri(pool: &PgPool, app_id: Uuid, uri: &str) -> Re
 }
 
 pub async fn create_app(pool: &PgPool, name: String) -> Result<Uuid> {
-    let rsa = Rsa::generate(2048).map_err(|_| Error::RsaGenFail)?;
-
-    let private = rsa
-        .private_key_to_pem()
-        .map_err(|_| Error::RsaPrivatePEMFail)?;
-    let public = rsa
-        .public_key_to_pem()
-        .map_err(|_| Error::RsaPublicPEMFail)?;
+    let mut tx = pool.begin().await.map_err(|_| Error::PgInsertFail)?;
 
     let sql = r"
         insert into app
-        (name, private_key, public_key)
-        values ($1, PGP_SYM_ENCRYPT($2, $3), $4)
+        (name)
+        values ($1)
         returning id
     ";
 
-    Ok(sqlx::query(sql)
+    let app_id: Uuid = sqlx::query(sql)
         .bind(name)
-        .bind(String::from_utf8(private).map_err(|_| Error::PgInsertFail)?)
-        .bind(env::var("PRIVATE_KEY_ENC_KEY").unwrap())
-        .bind(String::from_utf8(public).map_err(|_| Error::PgInsertFail)?)
-        .fetch_one(pool)
+        .fetch_one(&mut *tx)
         .await
         .map_err(|_| Error::PgInsertFail)?
-        .get("id"))
-}
+        .get("id");
 
-pub async fn get_private_key(pool: &PgPool, app_id: Uuid) -> Result<String> {
-    let sql = r"
-        select PGP_SYM_DECRYPT(private_key::bytea, $1) as private_key
-        from app
-        where id = $2
-    ";
+    insert_key(&mut tx, app_id).await?;
 
-    Ok(sqlx::query(sql)
-        .bind(env::var("PRIVATE_KEY_ENC_KEY").unwrap())
-        .bind(app_id)
-        .fetch_one(pool)
-        .await
-        .map_err(|_| Error::PgFetchFail)?
-        .get("private_key"))
-}
+    tx.commit().await.map_err(|_| Error::PgInsertFail)?;
 
-pub async fn get_public_key(pool: &PgPool, app_id: Uuid) -> Result<String> {
-    let sql = r"
-        select public_key
-        from app
-        where id = $1
-    ";
-
-    Ok(sqlx::query(sql)
-        .bind(app_id)
-        .fetch_one(pool)
-        .await
-        .map_err(|_| Error::PgFetchFail)?
-        .get("public_key"))
+    Ok(app_id)
 }
 
 #[derive(Debug, FromRow)]
@@ -94,16 +60,69 @@ pub async fn get_apps(pool: &PgPool) -> Result<Vec<AppNames>> {
         .map_err(|_| Error::PgFetchFail)
 }
 
+/// How the legacy token endpoints hand tokens to the client.
+#[derive(Debug, Clone, Copy, PartialEq, Type, Deserialize)]
+#[sqlx(type_name = "token_delivery", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum TokenDelivery {
+    /// `access` and `refresh` cookies on `BASE_DOMAIN`, for browser apps.
+    Cookie,
+    /// A JSON body, for mobile apps and apps on other domains.
+    Body,
+}
+
 #[derive(FromRow, Debug)]
 pub struct AppDB {
     pub id: Uuid,
     pub name: String,
-    pub public_key: String,
+    pub require_pkce: bool,
+    pub confidential: bool,
+    /// Scopes the app may request, see [`crate::api::auth::consent::SCOPES`].
+    pub scopes: Vec<String>,
+    pub token_delivery: TokenDelivery,
+    /// Providers users of the app may sign in with, by their login name.
+    pub providers: Vec<String>,
+    /// Shown on the hosted login page.
+    pub logo_url: Option<String>,
+    /// Discord scopes requested on top of `identify`, for the app's guild rules.
+    pub discord_scopes: Vec<String>,
+    #[sqlx(flatten)]
+    pub policy: SessionPolicy,
 }
 
 pub async fn get_app(pool: &PgPool, app_id: Uuid) -> Result<AppDB> {
     let sql = r"
-        select id, name, public_key
+        select id, name, require_pkce, client_secret is not null as confidential, scopes,
+            token_delivery, providers, logo_url, discord_scopes,
+            access_ttl, refresh_ttl, session_ttl, idle_timeout,
+            refresh_grace
+        from app
+        where id = $1
+    ";
+
+    sqlx::query_as(sql)
+        .bind(app_id)
+        .fetch_one(pool)
+        .await
+        .map_err(|_| Error::PgFetchFail)
+}
+
+/// Token lifetimes in seconds. `session_ttl` caps how long a login can be kept alive
+/// through refreshes, `idle_timeout` ends sessions that haven't refreshed in time.
+/// `refresh_grace` is how long a rotated refresh token keeps returning its successor.
+#[derive(FromRow, Debug, Clone)]
+pub struct SessionPolicy {
+    pub access_ttl: i32,
+    pub refresh_ttl: i32,
+    pub session_ttl: Option<i32>,
+    pub idle_timeout: Option<i32>,
+    pub refresh_grace: i32,
+}
+
+pub async fn get_session_policy(pool: &PgPool, app_id: Uuid) -> Result<SessionPolicy> {
+    let sql = r"
+        select access_ttl, refresh_ttl, session_ttl, idle_timeout,
+            refresh_grace
         from app
         where id = $1
     ";
@@ -115,6 +134,121 @@ pub async fn get_app(pool: &PgPool, app_id: Uuid) -> Result<AppDB> {
         .map_err(|_| Error::PgFetchFail)
 }
 
+pub async fn update_session_policy(
+    pool: &PgPool,
+    app_id: Uuid,
+    policy: &SessionPolicy,
+) -> Result<()> {
+    let sql = r"
+        update app
+        set access_ttl = $1, refresh_ttl = $2, session_ttl = $3, idle_timeout = $4,
+            refresh_grace = $5
+        where id = $6
+    ";
+
+    sqlx::query(sql)
+        .bind(policy.access_ttl)
+        .bind(policy.refresh_ttl)
+        .bind(policy.session_ttl)
+        .bind(policy.idle_timeout)
+        .bind(policy.refresh_grace)
+        .bind(app_id)
+        .execute(pool)
+        .await
+        .map_err(|_| Error::PgUpdateFail)?;
+
+    Ok(())
+}
+
+pub async fn set_scopes(pool: &PgPool, app_id: Uuid, scopes: &[String]) -> Result<()> {
+    let sql = r"
+        update app
+        set scopes = $1
+        where id = $2
+    ";
+
+    sqlx::query(sql)
+        .bind(scopes)
+        .bind(app_id)
+        .execute(pool)
+        .await
+        .map_err(|_| Error::PgUpdateFail)?;
+
+    Ok(())
+}
+
+pub async fn set_providers(pool: &PgPool, app_id: Uuid, providers: &[String]) -> Result<()> {
+    let sql = r"
+        update app
+        set providers = $1
+        where id = $2
+    ";
+
+    sqlx::query(sql)
+        .bind(providers)
+        .bind(app_id)
+        .execute(pool)
+        .await
+        .map_err(|_| Error::PgUpdateFail)?;
+
+    Ok(())
+}
+
+pub async fn set_logo_url(pool: &PgPool, app_id: Uuid, logo_url: Option<&str>) -> Result<()> {
+    let sql = r"
+        update app
+        set logo_url = $1
+        where id = $2
+    ";
+
+    sqlx::query(sql)
+        .bind(logo_url)
+        .bind(app_id)
+        .execute(pool)
+        .await
+        .map_err(|_| Error::PgUpdateFail)?;
+
+    Ok(())
+}
+
+pub async fn set_token_delivery(
+    pool: &PgPool,
+    app_id: Uuid,
+    token_delivery: TokenDelivery,
+) -> Result<()> {
+    let sql = r"
+        update app
+        set token_delivery = $1
+        where id = $2
+    ";
+
+    sqlx::query(sql)
+        .bind(token_delivery)
+        .bind(app_id)
+        .execute(pool)
+        .await
+        .map_err(|_| Error::PgUpdateFail)?;
+
+    Ok(())
+}
+
+pub async fn set_require_pkce(pool: &PgPool, app_id: Uuid, require_pkce: bool) -> Result<()> {
+    let sql = r"
+        update app
+        set require_pkce = $1
+        where id = $2
+    ";
+
+    sqlx::query(sql)
+        .bind(require_pkce)
+        .bind(app_id)
+        .execute(pool)
+        .await
+        .map_err(|_| Error::PgUpdateFail)?;
+
+    Ok(())
+}
+
 #[derive(Debug, FromRow)]
 pub struct RedirectUri {
     pub uri: String,
@@ -188,6 +322,50 @@ pub async fn update_redirect_uri(
         .map_err(|_| Error::PgUpdateFail)
 }
 
+/// Generates a new client secret, storing only its bcrypt hash. The plain secret is returned once.
+pub async fn rotate_client_secret(pool: &PgPool, app_id: Uuid) -> Result<String> {
+    let secret: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(48)
+        .map(char::from)
+        .collect();
+
+    let sql = r"
+        update app
+        set client_secret = crypt($1, gen_salt('bf'))
+        where id = $2
+    ";
+
+    let result = sqlx::query(sql)
+        .bind(&secret)
+        .bind(app_id)
+        .execute(pool)
+        .await
+        .map_err(|_| Error::PgUpdateFail)?;
+
+    if result.rows_affected() == 0 {
+        return Err(Error::PgNone);
+    }
+
+    Ok(secret)
+}
+
+pub async fn verify_client_secret(pool: &PgPool, app_id: Uuid, secret: &str) -> Result<bool> {
+    let sql = r"
+        select coalesce(client_secret = crypt($1, client_secret), false) as valid
+        from app
+        where id = $2
+    ";
+
+    Ok(sqlx::query(sql)
+        .bind(secret)
+        .bind(app_id)
+        .fetch_one(pool)
+        .await
+        .map_err(|_| Error::PgFetchFail)?
+        .get("valid"))
+}
+
 pub async fn remove_app(pool: &PgPool, app_id: Uuid) -> Result<()> {
     let sql = r"
         delete from app
@@ -0,0 +1,49 @@
+use sqlx::{types::Uuid, PgPool, Row};
+
+use crate::error::{Error, Result};
+
+/// The scopes a user has already agreed to share with an app.
+pub async fn get_consent(pool: &PgPool, app_id: Uuid, user_id: i32) -> Result<Vec<String>> {
+    let sql = r"
+        select scopes
+        from consent
+        where app_id = $1 and user_id = $2
+    ";
+
+    let consent = sqlx::query(sql)
+        .bind(app_id)
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await
+        .map_err(|_| Error::PgFetchFail)?;
+
+    Ok(consent.map(|row| row.get("scopes")).unwrap_or_default())
+}
+
+/// Adds scopes to what the user has agreed to, keeping earlier consent.
+pub async fn grant_consent(
+    pool: &PgPool,
+    app_id: Uuid,
+    user_id: i32,
+    scopes: &[String],
+) -> Result<()> {
+    let sql = r"
+        insert into consent
+        (app_id, user_id, scopes)
+        values ($1, $2, $3)
+        on conflict (app_id, user_id) do update
+        set scopes = array(
+            select distinct unnest(consent.scopes || excluded.scopes)
+        )
+    ";
+
+    sqlx::query(sql)
+        .bind(app_id)
+        .bind(user_id)
+        .bind(scopes)
+        .execute(pool)
+        .await
+        .map_err(|_| Error::PgInsertFail)?;
+
+    Ok(())
+}
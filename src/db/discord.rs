@@ -0,0 +1,163 @@
+use std::collections::BTreeMap;
+
+use sqlx::{types::Uuid, FromRow, PgPool};
+
+use crate::error::{Error, Result};
+
+/// Admits discord accounts in the guild, or only those with the role there when set.
+#[derive(Debug, FromRow)]
+pub struct DiscordRule {
+    pub id: i32,
+    pub guild_id: String,
+    pub role_id: Option<String>,
+}
+
+impl DiscordRule {
+    /// Checks the rule against the account's roles by guild, guilds it isn't in left out.
+    pub fn admits(&self, guild_roles: &BTreeMap<String, Vec<String>>) -> bool {
+        guild_roles
+            .get(&self.guild_id)
+            .is_some_and(|roles| match &self.role_id {
+                Some(role_id) => roles.contains(role_id),
+                None => true,
+            })
+    }
+}
+
+pub async fn get_discord_rules(pool: &PgPool, app_id: Uuid) -> Result<Vec<DiscordRule>> {
+    let sql = r"
+        select id, guild_id, role_id
+        from discord_rule
+        where app_id = $1
+        order by id
+    ";
+
+    sqlx::query_as(sql)
+        .bind(app_id)
+        .fetch_all(pool)
+        .await
+        .map_err(|_| Error::PgFetchFail)
+}
+
+pub async fn add_discord_rule(
+    pool: &PgPool,
+    app_id: Uuid,
+    guild_id: &str,
+    role_id: Option<&str>,
+) -> Result<()> {
+    let sql = r"
+        insert into discord_rule (app_id, guild_id, role_id)
+        values ($1, $2, $3)
+    ";
+
+    sqlx::query(sql)
+        .bind(app_id)
+        .bind(guild_id)
+        .bind(role_id)
+        .execute(pool)
+        .await
+        .map_err(|_| Error::PgInsertFail)?;
+
+    Ok(())
+}
+
+pub async fn remove_discord_rule(pool: &PgPool, app_id: Uuid, rule_id: i32) -> Result<()> {
+    let sql = r"
+        delete from discord_rule
+        where app_id = $1 and id = $2
+    ";
+
+    sqlx::query(sql)
+        .bind(app_id)
+        .bind(rule_id)
+        .execute(pool)
+        .await
+        .map_err(|_| Error::PgDeleteFail)?;
+
+    Ok(())
+}
+
+pub async fn set_discord_scopes(pool: &PgPool, app_id: Uuid, scopes: &[String]) -> Result<()> {
+    let sql = r"
+        update app
+        set discord_scopes = $1
+        where id = $2
+    ";
+
+    sqlx::query(sql)
+        .bind(scopes)
+        .bind(app_id)
+        .execute(pool)
+        .await
+        .map_err(|_| Error::PgUpdateFail)?;
+
+    Ok(())
+}
+
+/// Replaces the roles stored for the discord account with those read at this login.
+pub async fn set_guild_roles(
+    pool: &PgPool,
+    app_id: Uuid,
+    subject: &str,
+    guild_roles: &BTreeMap<String, Vec<String>>,
+) -> Result<()> {
+    let mut tx = pool.begin().await.map_err(|_| Error::PgInsertFail)?;
+
+    let sql = r"
+        delete from discord_guild_roles
+        where app_id = $1 and subject = $2
+    ";
+
+    sqlx::query(sql)
+        .bind(app_id)
+        .bind(subject)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| Error::PgDeleteFail)?;
+
+    let sql = r"
+        insert into discord_guild_roles (app_id, subject, guild_id, roles)
+        values ($1, $2, $3, $4)
+    ";
+
+    for (guild_id, roles) in guild_roles {
+        sqlx::query(sql)
+            .bind(app_id)
+            .bind(subject)
+            .bind(guild_id)
+            .bind(roles)
+            .execute(&mut *tx)
+            .await
+            .map_err(|_| Error::PgInsertFail)?;
+    }
+
+    tx.commit().await.map_err(|_| Error::PgInsertFail)?;
+
+    Ok(())
+}
+
+/// The user's roles by guild, through their linked discord account, left to the guilds the
+/// app's rules still name.
+pub async fn get_guild_roles(
+    pool: &PgPool,
+    app_id: Uuid,
+    user_id: i32,
+) -> Result<BTreeMap<String, Vec<String>>> {
+    let sql = r"
+        select r.guild_id, r.roles
+        from discord_guild_roles r
+        join user_identities i
+            on i.app_id = r.app_id and i.provider = 'discord' and i.subject = r.subject
+        where r.app_id = $1 and i.user_id = $2
+            and r.guild_id in (select guild_id from discord_rule where app_id = $1)
+    ";
+
+    let rows: Vec<(String, Vec<String>)> = sqlx::query_as(sql)
+        .bind(app_id)
+        .bind(user_id)
+        .fetch_all(pool)
+        .await
+        .map_err(|_| Error::PgFetchFail)?;
+
+    Ok(rows.into_iter().collect())
+}
@@ -0,0 +1,161 @@
+use std::env;
+
+use openssl::rsa::Rsa;
+use sqlx::{types::Uuid, FromRow, PgPool, Postgres, Row, Transaction, Type};
+
+use crate::error::{Error, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq, Type)]
+#[sqlx(type_name = "key_status", rename_all = "lowercase")]
+pub enum KeyStatus {
+    /// Signs new tokens and verifies existing ones, exactly one per app.
+    Active,
+    /// Still verifies tokens issued before the last rotation.
+    Retiring,
+    /// Kept for bookkeeping only.
+    Retired,
+}
+
+#[derive(Debug, FromRow)]
+pub struct AppKey {
+    pub id: i32,
+    pub public_key: String,
+    pub status: KeyStatus,
+    pub created_at: String,
+}
+
+fn generate_key() -> Result<(String, String)> {
+    let rsa = Rsa::generate(2048).map_err(|_| Error::RsaGenFail)?;
+
+    let private = rsa
+        .private_key_to_pem()
+        .map_err(|_| Error::RsaPrivatePEMFail)?;
+    let public = rsa
+        .public_key_to_pem()
+        .map_err(|_| Error::RsaPublicPEMFail)?;
+
+    Ok((
+        String::from_utf8(private).map_err(|_| Error::PgInsertFail)?,
+        String::from_utf8(public).map_err(|_| Error::PgInsertFail)?,
+    ))
+}
+
+/// Generates a new key pair and stores it as the app's active key.
+pub async fn insert_key(tx: &mut Transaction<'_, Postgres>, app_id: Uuid) -> Result<i32> {
+    let (private, public) = generate_key()?;
+
+    let sql = r"
+        insert into app_key
+        (app_id, private_key, public_key)
+        values ($1, PGP_SYM_ENCRYPT($2, $3), $4)
+        returning id
+    ";
+
+    Ok(sqlx::query(sql)
+        .bind(app_id)
+        .bind(private)
+        .bind(env::var("PRIVATE_KEY_ENC_KEY").unwrap())
+        .bind(public)
+        .fetch_one(&mut **tx)
+        .await
+        .map_err(|_| Error::PgInsertFail)?
+        .get("id"))
+}
+
+/// Moves the active key to `retiring` and generates a new active key.
+pub async fn rotate_key(pool: &PgPool, app_id: Uuid) -> Result<i32> {
+    let mut tx = pool.begin().await.map_err(|_| Error::PgUpdateFail)?;
+
+    let sql = r"
+        update app_key
+        set status = 'retiring'
+        where app_id = $1 and status = 'active'
+    ";
+
+    sqlx::query(sql)
+        .bind(app_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| Error::PgUpdateFail)?;
+
+    let key_id = insert_key(&mut tx, app_id).await?;
+
+    tx.commit().await.map_err(|_| Error::PgUpdateFail)?;
+
+    Ok(key_id)
+}
+
+/// Stops a retiring key from verifying tokens.
+pub async fn retire_key(pool: &PgPool, app_id: Uuid, key_id: i32) -> Result<()> {
+    let sql = r"
+        update app_key
+        set status = 'retired'
+        where app_id = $1 and id = $2 and status = 'retiring'
+    ";
+
+    let result = sqlx::query(sql)
+        .bind(app_id)
+        .bind(key_id)
+        .execute(pool)
+        .await
+        .map_err(|_| Error::PgUpdateFail)?;
+
+    if result.rows_affected() == 0 {
+        return Err(Error::PgNone);
+    }
+
+    Ok(())
+}
+
+pub async fn get_private_key(pool: &PgPool, app_id: Uuid) -> Result<String> {
+    let sql = r"
+        select PGP_SYM_DECRYPT(private_key::bytea, $1) as private_key
+        from app_key
+        where app_id = $2 and status = 'active'
+    ";
+
+    Ok(sqlx::query(sql)
+        .bind(env::var("PRIVATE_KEY_ENC_KEY").unwrap())
+        .bind(app_id)
+        .fetch_one(pool)
+        .await
+        .map_err(|_| Error::PgFetchFail)?
+        .get("private_key"))
+}
+
+/// Returns the public keys that tokens of the app may be verified with.
+pub async fn get_public_keys(pool: &PgPool, app_id: Uuid) -> Result<Vec<String>> {
+    let sql = r"
+        select public_key
+        from app_key
+        where app_id = $1 and status in ('active', 'retiring')
+        order by id desc
+    ";
+
+    let keys = sqlx::query(sql)
+        .bind(app_id)
+        .fetch_all(pool)
+        .await
+        .map_err(|_| Error::PgFetchFail)?;
+
+    if keys.is_empty() {
+        return Err(Error::PgNone);
+    }
+
+    Ok(keys.iter().map(|row| row.get("public_key")).collect())
+}
+
+pub async fn get_keys(pool: &PgPool, app_id: Uuid) -> Result<Vec<AppKey>> {
+    let sql = r"
+        select id, public_key, status, created_at::text as created_at
+        from app_key
+        where app_id = $1
+        order by id desc
+    ";
+
+    sqlx::query_as(sql)
+        .bind(app_id)
+        .fetch_all(pool)
+        .await
+        .map_err(|_| Error::PgFetchFail)
+}
@@ -1,2 +1,6 @@
 pub mod app;
+pub mod consent;
+pub mod discord;
+pub mod key;
+pub mod provider;
 pub mod user;
@@ -0,0 +1,279 @@
+use std::env;
+
+use serde::Deserialize;
+use sqlx::types::Uuid;
+use sqlx::{FromRow, PgPool, Type};
+
+use crate::error::{Error, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq, Type, Deserialize)]
+#[sqlx(type_name = "provider_kind", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum ProviderKind {
+    /// Users are read from a verified ID token, topped up from the userinfo endpoint.
+    Oidc,
+    /// Users are read from the userinfo endpoint only.
+    OAuth2,
+}
+
+/// An upstream identity provider registered from the dashboard.
+#[derive(Debug, FromRow)]
+pub struct Provider {
+    pub slug: String,
+    pub kind: ProviderKind,
+    pub issuer: Option<String>,
+    pub auth_url: Option<String>,
+    pub token_url: Option<String>,
+    pub userinfo_url: Option<String>,
+    pub jwks_url: Option<String>,
+    pub client_id: String,
+    pub client_secret: String,
+    pub scopes: String,
+    pub id_claim: String,
+    pub username_claim: String,
+    pub avatar_claim: String,
+}
+
+#[derive(Debug, FromRow)]
+pub struct ProviderNames {
+    pub id: i32,
+    pub slug: String,
+    pub name: String,
+}
+
+/// A provider as entered on the dashboard, empty fields standing for unset.
+#[derive(Deserialize)]
+pub struct NewProvider {
+    pub slug: String,
+    pub name: String,
+    pub kind: ProviderKind,
+    pub issuer: String,
+    pub auth_url: String,
+    pub token_url: String,
+    pub userinfo_url: String,
+    pub jwks_url: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub scopes: String,
+    pub id_claim: String,
+    pub username_claim: String,
+    pub avatar_claim: String,
+}
+
+pub async fn get_providers(pool: &PgPool) -> Result<Vec<ProviderNames>> {
+    let sql = r"
+        select id, slug, name
+        from provider
+        order by name
+    ";
+
+    sqlx::query_as(sql)
+        .fetch_all(pool)
+        .await
+        .map_err(|_| Error::PgFetchFail)
+}
+
+pub async fn get_provider(pool: &PgPool, slug: &str) -> Result<Option<Provider>> {
+    let sql = r"
+        select slug, kind, issuer, auth_url, token_url, userinfo_url, jwks_url,
+            client_id, PGP_SYM_DECRYPT(client_secret::bytea, $1) as client_secret, scopes,
+            id_claim, username_claim, avatar_claim
+        from provider
+        where slug = $2
+    ";
+
+    sqlx::query_as(sql)
+        .bind(env::var("PRIVATE_KEY_ENC_KEY").unwrap())
+        .bind(slug)
+        .fetch_optional(pool)
+        .await
+        .map_err(|_| Error::PgFetchFail)
+}
+
+pub async fn create_provider(pool: &PgPool, provider: &NewProvider) -> Result<i32> {
+    let sql = r"
+        insert into provider
+        (slug, name, kind, issuer, auth_url, token_url, userinfo_url, jwks_url, client_id,
+            client_secret, scopes, id_claim, username_claim, avatar_claim)
+        values ($1, $2, $3, $4, $5, $6, $7, $8, $9, PGP_SYM_ENCRYPT($10, $11), $12, $13, $14, $15)
+        returning id
+    ";
+
+    let optional = |value: &str| match value.trim() {
+        "" => None,
+        value => Some(value.to_string()),
+    };
+
+    sqlx::query_scalar(sql)
+        .bind(provider.slug.trim())
+        .bind(provider.name.trim())
+        .bind(provider.kind)
+        .bind(optional(&provider.issuer))
+        .bind(optional(&provider.auth_url))
+        .bind(optional(&provider.token_url))
+        .bind(optional(&provider.userinfo_url))
+        .bind(optional(&provider.jwks_url))
+        .bind(provider.client_id.trim())
+        .bind(&provider.client_secret)
+        .bind(env::var("PRIVATE_KEY_ENC_KEY").unwrap())
+        .bind(provider.scopes.trim())
+        .bind(provider.id_claim.trim())
+        .bind(provider.username_claim.trim())
+        .bind(provider.avatar_claim.trim())
+        .fetch_one(pool)
+        .await
+        .map_err(|_| Error::PgInsertFail)
+}
+
+/// Removes the provider along with every identity linked through it, and turns it off for
+/// the apps that had it enabled.
+pub async fn remove_provider(pool: &PgPool, id: i32) -> Result<()> {
+    let mut tx = pool.begin().await.map_err(|_| Error::PgDeleteFail)?;
+
+    let sql = r"
+        delete from user_identities
+        where provider = (select slug from provider where id = $1)
+    ";
+
+    sqlx::query(sql)
+        .bind(id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| Error::PgDeleteFail)?;
+
+    let sql = r"
+        delete from app_provider_credentials
+        where provider = (select slug from provider where id = $1)
+    ";
+
+    sqlx::query(sql)
+        .bind(id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| Error::PgDeleteFail)?;
+
+    let sql = r"
+        update app
+        set providers = array_remove(providers, (select slug from provider where id = $1))
+    ";
+
+    sqlx::query(sql)
+        .bind(id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| Error::PgDeleteFail)?;
+
+    let sql = r"
+        delete from provider
+        where id = $1
+    ";
+
+    sqlx::query(sql)
+        .bind(id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| Error::PgDeleteFail)?;
+
+    tx.commit().await.map_err(|_| Error::PgDeleteFail)?;
+
+    Ok(())
+}
+
+/// An app's own credentials at a provider, taking over from the server wide ones.
+#[derive(Debug, FromRow)]
+pub struct AppCredentials {
+    pub client_id: Option<String>,
+    pub client_secret: String,
+}
+
+/// The providers an app has credentials for, without the secrets.
+#[derive(Debug, FromRow)]
+pub struct AppCredentialNames {
+    pub provider: String,
+    pub client_id: Option<String>,
+    pub created_at: String,
+}
+
+pub async fn get_app_credentials(
+    pool: &PgPool,
+    app_id: Uuid,
+    provider: &str,
+) -> Result<Option<AppCredentials>> {
+    let sql = r"
+        select client_id, PGP_SYM_DECRYPT(client_secret::bytea, $1) as client_secret
+        from app_provider_credentials
+        where app_id = $2 and provider = $3
+    ";
+
+    sqlx::query_as(sql)
+        .bind(env::var("PRIVATE_KEY_ENC_KEY").unwrap())
+        .bind(app_id)
+        .bind(provider)
+        .fetch_optional(pool)
+        .await
+        .map_err(|_| Error::PgFetchFail)
+}
+
+pub async fn get_app_credential_names(
+    pool: &PgPool,
+    app_id: Uuid,
+) -> Result<Vec<AppCredentialNames>> {
+    let sql = r"
+        select provider, client_id, created_at::text as created_at
+        from app_provider_credentials
+        where app_id = $1
+        order by provider
+    ";
+
+    sqlx::query_as(sql)
+        .bind(app_id)
+        .fetch_all(pool)
+        .await
+        .map_err(|_| Error::PgFetchFail)
+}
+
+/// Stores the app's credentials at the provider, replacing any it already had.
+pub async fn set_app_credentials(
+    pool: &PgPool,
+    app_id: Uuid,
+    provider: &str,
+    client_id: Option<&str>,
+    client_secret: &str,
+) -> Result<()> {
+    let sql = r"
+        insert into app_provider_credentials (app_id, provider, client_id, client_secret)
+        values ($1, $2, $3, PGP_SYM_ENCRYPT($4, $5))
+        on conflict (app_id, provider) do update
+        set client_id = excluded.client_id,
+            client_secret = excluded.client_secret,
+            created_at = now()
+    ";
+
+    sqlx::query(sql)
+        .bind(app_id)
+        .bind(provider)
+        .bind(client_id)
+        .bind(client_secret)
+        .bind(env::var("PRIVATE_KEY_ENC_KEY").unwrap())
+        .execute(pool)
+        .await
+        .map_err(|_| Error::PgInsertFail)?;
+
+    Ok(())
+}
+
+pub async fn remove_app_credentials(pool: &PgPool, app_id: Uuid, provider: &str) -> Result<()> {
+    let sql = r"
+        delete from app_provider_credentials
+        where app_id = $1 and provider = $2
+    ";
+
+    sqlx::query(sql)
+        .bind(app_id)
+        .bind(provider)
+        .execute(pool)
+        .await
+        .map_err(|_| Error::PgDeleteFail)?;
+
+    Ok(())
+}
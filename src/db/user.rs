@@ -1,89 +1,553 @@
+use std::collections::BTreeMap;
+
 use serde::{Deserialize, Serialize};
-use sqlx::{types::Uuid, FromRow, PgPool, Type};
+use sqlx::{types::Uuid, FromRow, PgPool};
 
+use crate::db::discord::get_guild_roles;
 use crate::error::{Error, Result};
 
-#[derive(Debug, FromRow, Serialize, Deserialize, Clone)]
+/// Providers that had an account field on the user before identities, still filled in
+/// for clients reading them.
+const LEGACY_PROVIDERS: [&str; 4] = ["discord", "steam", "google", "github"];
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct User {
     #[serde(skip)]
     pub app_id: Uuid,
     pub user_id: i32,
+    #[serde(default)]
     pub discord: Account,
+    #[serde(default)]
     pub steam: Account,
+    #[serde(default)]
+    pub google: Account,
+    #[serde(default)]
+    pub github: Account,
+    /// Every account linked to the user, including those of registered providers.
+    #[serde(default)]
+    pub identities: Vec<Identity>,
+    /// Roles in the guilds of the app's discord rules, by guild id.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub discord_roles: BTreeMap<String, Vec<String>>,
     pub admin: bool,
 }
 
-#[derive(Debug, FromRow, Type, Serialize, Deserialize, Clone)]
+impl User {
+    fn new(app_id: Uuid, user_id: i32, admin: bool, identities: Vec<Identity>) -> Self {
+        let account = |provider: &str| {
+            identities
+                .iter()
+                .find(|identity| identity.provider == provider)
+                .map(Account::from)
+                .unwrap_or_default()
+        };
+
+        User {
+            app_id,
+            user_id,
+            discord: account("discord"),
+            steam: account("steam"),
+            google: account("google"),
+            github: account("github"),
+            admin,
+            identities,
+            discord_roles: BTreeMap::new(),
+        }
+    }
+
+    /// The parts of the user an app was granted, `None` being a token from before scopes.
+    /// Identities of built-in providers come with their own scope, those of registered
+    /// providers with `profile`.
+    pub fn with_scopes(&self, scope: Option<&str>) -> User {
+        let Some(scope) = scope else {
+            return self.clone();
+        };
+
+        let granted = |name: &str| scope.split(' ').any(|s| s == name);
+
+        let identities = self
+            .identities
+            .iter()
+            .filter(
+                |identity| match LEGACY_PROVIDERS.contains(&identity.provider.as_str()) {
+                    true => granted(&identity.provider),
+                    false => granted("profile"),
+                },
+            )
+            .cloned()
+            .collect();
+
+        let mut user = User::new(
+            self.app_id,
+            self.user_id,
+            granted("admin") && self.admin,
+            identities,
+        );
+
+        if granted("discord") {
+            user.discord_roles = self.discord_roles.clone();
+        }
+
+        user
+    }
+}
+
+/// An account as a provider reports it.
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
 pub struct Account {
     pub id: Option<String>,
     pub avatar: Option<String>,
     pub username: Option<String>,
 }
 
-pub async fn get_user(pool: &PgPool, app_id: Uuid, id: &str) -> Result<Option<User>> {
+impl From<&Identity> for Account {
+    fn from(identity: &Identity) -> Self {
+        Account {
+            id: Some(identity.subject.clone()),
+            avatar: identity.avatar.clone(),
+            username: identity.username.clone(),
+        }
+    }
+}
+
+/// A provider account linked to a user.
+#[derive(Debug, FromRow, Serialize, Deserialize, Clone)]
+pub struct Identity {
+    pub provider: String,
+    pub subject: String,
+    pub username: Option<String>,
+    pub avatar: Option<String>,
+}
+
+#[derive(FromRow)]
+struct UserRow {
+    user_id: i32,
+    admin: bool,
+}
+
+async fn get_identities(pool: &PgPool, app_id: Uuid, user_id: i32) -> Result<Vec<Identity>> {
+    let sql = r"
+        select provider, subject, username, avatar
+        from user_identities
+        where app_id = $1 and user_id = $2
+        order by created_at
+    ";
+
+    sqlx::query_as(sql)
+        .bind(app_id)
+        .bind(user_id)
+        .fetch_all(pool)
+        .await
+        .map_err(|_| Error::PgFetchFail)
+}
+
+pub async fn get_user_by_id(pool: &PgPool, app_id: Uuid, user_id: i32) -> Result<Option<User>> {
     let sql = r"
-        select * from users
-        where app_id = $1 and ((discord).id like $2 or (steam).id like $3)
+        select user_id, admin from users
+        where app_id = $1 and user_id = $2
     ";
 
-    let query = sqlx::query_as::<_, User>(sql)
+    let row = sqlx::query_as::<_, UserRow>(sql)
         .bind(app_id)
-        .bind(&id)
-        .bind(&id)
-        .bind(&id);
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await
+        .map_err(|_| Error::PgFetchFail)?;
 
-    let user = query.fetch_optional(pool).await.map_err(|e| {
-        println!("{e:?}");
+    let Some(row) = row else {
+        return Ok(None);
+    };
 
-        return Error::PgFetchFail;
-    })?;
+    let identities = get_identities(pool, app_id, row.user_id).await?;
 
-    Ok(user)
+    let mut user = User::new(app_id, row.user_id, row.admin, identities);
+    user.discord_roles = get_guild_roles(pool, app_id, row.user_id).await?;
+
+    Ok(Some(user))
 }
 
+/// The user the provider account is linked to, if any.
+pub async fn get_identity_user(
+    pool: &PgPool,
+    app_id: Uuid,
+    provider: &str,
+    subject: &str,
+) -> Result<Option<i32>> {
+    let sql = r"
+        select user_id
+        from user_identities
+        where app_id = $1 and provider = $2 and subject = $3
+    ";
+
+    sqlx::query_scalar(sql)
+        .bind(app_id)
+        .bind(provider)
+        .bind(subject)
+        .fetch_optional(pool)
+        .await
+        .map_err(|_| Error::PgFetchFail)
+}
+
+/// Creates a user signed in with the provider account.
 pub async fn create_user(
     pool: &PgPool,
     app_id: Uuid,
-    discord: Option<&Account>,
-    steam: Option<&Account>,
-) -> Result<()> {
+    provider: &str,
+    account: &Account,
+) -> Result<i32> {
+    let mut tx = pool.begin().await.map_err(|_| Error::PgInsertFail)?;
+
     let sql = r"
         insert into users
-        (app_id, steam, discord)
-        values ($1, row($2, $3, $4), row($5, $6, $7))
+        (app_id)
+        values ($1)
+        returning user_id
+    ";
+
+    let user_id: i32 = sqlx::query_scalar(sql)
+        .bind(app_id)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|_| Error::PgInsertFail)?;
+
+    let sql = r"
+        insert into user_identities
+        (app_id, user_id, provider, subject, username, avatar)
+        values ($1, $2, $3, $4, $5, $6)
+    ";
+
+    sqlx::query(sql)
+        .bind(app_id)
+        .bind(user_id)
+        .bind(provider)
+        .bind(&account.id)
+        .bind(&account.username)
+        .bind(&account.avatar)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| Error::PgInsertFail)?;
+
+    tx.commit().await.map_err(|_| Error::PgInsertFail)?;
+
+    Ok(user_id)
+}
+
+/// Refreshes the username and avatar of a linked provider account.
+pub async fn update_identity(
+    pool: &PgPool,
+    app_id: Uuid,
+    provider: &str,
+    account: &Account,
+) -> Result<()> {
+    let sql = r"
+        update user_identities
+        set username = $1, avatar = $2, updated_at = now()
+        where app_id = $3 and provider = $4 and subject = $5
+    ";
+
+    sqlx::query(sql)
+        .bind(&account.username)
+        .bind(&account.avatar)
+        .bind(app_id)
+        .bind(provider)
+        .bind(&account.id)
+        .execute(pool)
+        .await
+        .map_err(|_| Error::PgUpdateFail)?;
+
+    Ok(())
+}
+
+/// Marks the provider accounts as refreshed without changing them, for accounts the
+/// provider no longer returns.
+pub async fn touch_identities(
+    pool: &PgPool,
+    app_id: Uuid,
+    provider: &str,
+    subjects: &[String],
+) -> Result<()> {
+    let sql = r"
+        update user_identities
+        set updated_at = now()
+        where app_id = $1 and provider = $2 and subject = any($3)
     ";
 
     sqlx::query(sql)
         .bind(app_id)
-        .bind(steam.map(|s| &s.id))
-        .bind(steam.map(|s| &s.avatar))
-        .bind(steam.map(|s| &s.username))
-        .bind(discord.map(|d| &d.id))
-        .bind(discord.map(|d| &d.avatar))
-        .bind(discord.map(|d| &d.username))
+        .bind(provider)
+        .bind(subjects)
+        .execute(pool)
+        .await
+        .map_err(|_| Error::PgUpdateFail)?;
+
+    Ok(())
+}
+
+/// A linked account whose username and avatar are due for a refresh.
+#[derive(Debug, FromRow)]
+pub struct StaleIdentity {
+    pub app_id: Uuid,
+    pub user_id: i32,
+    pub subject: String,
+}
+
+/// The provider's accounts not refreshed in the last `max_age` seconds, oldest first.
+pub async fn get_stale_identities(
+    pool: &PgPool,
+    provider: &str,
+    max_age: i64,
+) -> Result<Vec<StaleIdentity>> {
+    let sql = r"
+        select app_id, user_id, subject
+        from user_identities
+        where provider = $1 and updated_at < now() - $2::float8 * interval '1 second'
+        order by updated_at
+    ";
+
+    sqlx::query_as(sql)
+        .bind(provider)
+        .bind(max_age as f64)
+        .fetch_all(pool)
+        .await
+        .map_err(|_| Error::PgFetchFail)
+}
+
+/// Links the provider account to the user, refreshing it if already linked to them.
+/// Returns `false` when the account belongs to another user of the app.
+pub async fn link_identity(
+    pool: &PgPool,
+    app_id: Uuid,
+    user_id: i32,
+    provider: &str,
+    account: &Account,
+) -> Result<bool> {
+    let sql = r"
+        insert into user_identities
+        (app_id, user_id, provider, subject, username, avatar)
+        values ($1, $2, $3, $4, $5, $6)
+        on conflict (app_id, provider, subject)
+        do update set username = excluded.username, avatar = excluded.avatar, updated_at = now()
+        where user_identities.user_id = excluded.user_id
+    ";
+
+    let result = sqlx::query(sql)
+        .bind(app_id)
+        .bind(user_id)
+        .bind(provider)
+        .bind(&account.id)
+        .bind(&account.username)
+        .bind(&account.avatar)
         .execute(pool)
         .await
         .map_err(|_| Error::PgInsertFail)?;
 
+    Ok(result.rows_affected() == 1)
+}
+
+/// Unlinks a provider account from the user, refusing to remove their last one.
+pub async fn unlink_identity(
+    pool: &PgPool,
+    app_id: Uuid,
+    user_id: i32,
+    provider: &str,
+    subject: &str,
+) -> Result<()> {
+    let mut tx = pool.begin().await.map_err(|_| Error::PgDeleteFail)?;
+
+    // locks the user so concurrent unlinks can't remove the last two identities together
+    let sql = r"
+        select count(*)
+        from user_identities
+        where app_id = $1 and user_id = (
+            select user_id from users
+            where app_id = $1 and user_id = $2
+            for update
+        )
+    ";
+
+    let linked: i64 = sqlx::query_scalar(sql)
+        .bind(app_id)
+        .bind(user_id)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|_| Error::PgFetchFail)?;
+
+    if linked <= 1 {
+        return Err(Error::AuthLastIdentity);
+    }
+
+    let sql = r"
+        delete from user_identities
+        where app_id = $1 and user_id = $2 and provider = $3 and subject = $4
+    ";
+
+    let result = sqlx::query(sql)
+        .bind(app_id)
+        .bind(user_id)
+        .bind(provider)
+        .bind(subject)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| Error::PgDeleteFail)?;
+
+    if result.rows_affected() == 0 {
+        return Err(Error::PgNone);
+    }
+
+    tx.commit().await.map_err(|_| Error::PgDeleteFail)?;
+
     Ok(())
 }
 
-pub async fn update_user_steam(pool: &PgPool, app_id: Uuid, steam: &Account) -> Result<()> {
+/// Folds the duplicate user into the primary one: their identities and consent move
+/// over, admin carries over, and the duplicate's id is recorded as merged.
+pub async fn merge_users(pool: &PgPool, app_id: Uuid, primary: i32, duplicate: i32) -> Result<()> {
+    if primary == duplicate {
+        return Err(Error::AuthInvalidParams);
+    }
+
+    let mut tx = pool.begin().await.map_err(|_| Error::PgUpdateFail)?;
+
+    let sql = r"
+        select user_id, admin from users
+        where app_id = $1 and user_id in ($2, $3)
+        for update
+    ";
+
+    let users: Vec<UserRow> = sqlx::query_as(sql)
+        .bind(app_id)
+        .bind(primary)
+        .bind(duplicate)
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(|_| Error::PgFetchFail)?;
+
+    if users.len() != 2 {
+        return Err(Error::PgNone);
+    }
+
+    let sql = r"
+        update user_identities
+        set user_id = $2
+        where app_id = $1 and user_id = $3
+    ";
+
+    sqlx::query(sql)
+        .bind(app_id)
+        .bind(primary)
+        .bind(duplicate)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| Error::PgUpdateFail)?;
+
+    let sql = r"
+        insert into consent
+        (app_id, user_id, scopes)
+        select app_id, $2, scopes
+        from consent
+        where app_id = $1 and user_id = $3
+        on conflict (app_id, user_id) do update
+        set scopes = array(
+            select distinct unnest(consent.scopes || excluded.scopes)
+        )
+    ";
+
+    sqlx::query(sql)
+        .bind(app_id)
+        .bind(primary)
+        .bind(duplicate)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| Error::PgUpdateFail)?;
+
     let sql = r"
         update users
-        set steam = row($1, $2, $3)
-        where app_id = $4 and (steam).id like $5
+        set admin = admin or $3
+        where app_id = $1 and user_id = $2
     ";
 
     sqlx::query(sql)
-        .bind(&steam.id)
-        .bind(&steam.avatar)
-        .bind(&steam.username)
         .bind(app_id)
-        .bind(&steam.id)
-        .execute(pool)
+        .bind(primary)
+        .bind(
+            users
+                .iter()
+                .any(|user| user.user_id == duplicate && user.admin),
+        )
+        .execute(&mut *tx)
         .await
         .map_err(|_| Error::PgUpdateFail)?;
 
+    // ids merged into the duplicate earlier now point at the primary as well
+    let sql = r"
+        update user_merge
+        set user_id = $2
+        where app_id = $1 and user_id = $3
+    ";
+
+    sqlx::query(sql)
+        .bind(app_id)
+        .bind(primary)
+        .bind(duplicate)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| Error::PgUpdateFail)?;
+
+    let sql = r"
+        insert into user_merge
+        (app_id, old_user_id, user_id)
+        values ($1, $3, $2)
+    ";
+
+    sqlx::query(sql)
+        .bind(app_id)
+        .bind(primary)
+        .bind(duplicate)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| Error::PgInsertFail)?;
+
+    let sql = r"
+        delete from users
+        where app_id = $1 and user_id = $2
+    ";
+
+    sqlx::query(sql)
+        .bind(app_id)
+        .bind(duplicate)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| Error::PgDeleteFail)?;
+
+    tx.commit().await.map_err(|_| Error::PgUpdateFail)?;
+
     Ok(())
 }
+
+#[derive(Debug, FromRow, Serialize)]
+pub struct UserMerge {
+    pub old_user_id: i32,
+    pub user_id: i32,
+    pub merged_at: String,
+}
+
+/// Users merged into another one, oldest first, optionally only those after `since`.
+pub async fn get_merges(
+    pool: &PgPool,
+    app_id: Uuid,
+    since: Option<&str>,
+) -> Result<Vec<UserMerge>> {
+    let sql = r"
+        select old_user_id, user_id, merged_at::text as merged_at
+        from user_merge
+        where app_id = $1 and ($2::timestamptz is null or merged_at > $2::timestamptz)
+        order by merged_at
+    ";
+
+    sqlx::query_as(sql)
+        .bind(app_id)
+        .bind(since)
+        .fetch_all(pool)
+        .await
+        .map_err(|_| Error::PgFetchFail)
+}
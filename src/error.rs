@@ -11,7 +11,23 @@ pub enum Error {
     AuthUserParseFail,
     AuthMissingState,
     AuthMissingCookie,
+    AuthMissingBearer,
     AuthInvalidParams,
+    AuthPkceRequired,
+    AuthPkceMismatch,
+    AuthRedirectMismatch,
+    AuthRefreshReuse,
+    AuthLastIdentity,
+    AuthProviderUnconfigured,
+
+    OAuthInvalidRequest,
+    OAuthInvalidClient,
+    OAuthInvalidGrant,
+    OAuthUnsupportedGrantType,
+    OAuthAuthorizationPending,
+    OAuthSlowDown,
+    OAuthExpiredToken,
+    OAuthAccessDenied,
 
     RedisSetFail,
     RedisExpireFail,
@@ -44,6 +60,29 @@ pub enum Error {
 pub enum ClientError {
     NO_AUTH,
     SERVICE_ERROR,
+    LAST_IDENTITY,
+
+    // RFC 6749 error codes
+    invalid_request,
+    invalid_client,
+    invalid_grant,
+    unsupported_grant_type,
+
+    // RFC 8628 error codes
+    authorization_pending,
+    slow_down,
+    expired_token,
+    access_denied,
+}
+
+impl ClientError {
+    /// OAuth errors are returned as a flat `{"error": code}` body as required by RFC 6749.
+    pub fn is_oauth(&self) -> bool {
+        !matches!(
+            self,
+            Self::NO_AUTH | Self::SERVICE_ERROR | Self::LAST_IDENTITY
+        )
+    }
 }
 
 impl IntoResponse for Error {
@@ -64,9 +103,30 @@ impl Error {
             | Self::JwtRefreshGenFail
             | Self::JwtInvalidToken
             | Self::AuthMissingState
-            | Self::AuthInvalidParams => (StatusCode::FORBIDDEN, ClientError::NO_AUTH),
-
-            Self::AuthMissingCookie => (StatusCode::UNAUTHORIZED, ClientError::NO_AUTH),
+            | Self::AuthInvalidParams
+            | Self::AuthPkceRequired
+            | Self::AuthPkceMismatch
+            | Self::AuthRedirectMismatch
+            | Self::AuthRefreshReuse => (StatusCode::FORBIDDEN, ClientError::NO_AUTH),
+
+            Self::AuthMissingCookie | Self::AuthMissingBearer => {
+                (StatusCode::UNAUTHORIZED, ClientError::NO_AUTH)
+            }
+
+            Self::AuthLastIdentity => (StatusCode::CONFLICT, ClientError::LAST_IDENTITY),
+
+            Self::OAuthInvalidRequest => (StatusCode::BAD_REQUEST, ClientError::invalid_request),
+            Self::OAuthInvalidClient => (StatusCode::UNAUTHORIZED, ClientError::invalid_client),
+            Self::OAuthInvalidGrant => (StatusCode::BAD_REQUEST, ClientError::invalid_grant),
+            Self::OAuthUnsupportedGrantType => {
+                (StatusCode::BAD_REQUEST, ClientError::unsupported_grant_type)
+            }
+            Self::OAuthAuthorizationPending => {
+                (StatusCode::BAD_REQUEST, ClientError::authorization_pending)
+            }
+            Self::OAuthSlowDown => (StatusCode::BAD_REQUEST, ClientError::slow_down),
+            Self::OAuthExpiredToken => (StatusCode::BAD_REQUEST, ClientError::expired_token),
+            Self::OAuthAccessDenied => (StatusCode::BAD_REQUEST, ClientError::access_denied),
 
             _ => (
                 StatusCode::INTERNAL_SERVER_ERROR,
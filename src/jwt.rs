@@ -1,6 +1,19 @@
+use std::env;
 use std::time::{SystemTime, UNIX_EPOCH};
 
-use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::jwk::{
+    AlgorithmParameters, CommonParameters, Jwk, KeyAlgorithm, PublicKeyUse, RSAKeyParameters,
+    RSAKeyType,
+};
+use jsonwebtoken::{
+    decode, decode_header, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation,
+};
+use openssl::pkey::HasPublic;
+use openssl::rsa::Rsa;
+use openssl::sha::sha256;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
 use serde::{Deserialize, Serialize};
 
 use crate::{
@@ -8,54 +21,363 @@ use crate::{
     error::{Error, Result},
 };
 
-pub fn gen_access_token(user: &User, app_id: &String, private_key: &[u8]) -> Result<String> {
-    let claims = Claims::new(&user, app_id, 300)?;
+pub fn gen_access_token(
+    user: &User,
+    app_id: &String,
+    scope: Option<&str>,
+    ttl: usize,
+    private_key: &[u8],
+) -> Result<String> {
+    let now = now()?;
+    let mut claims = Claims::new(user, app_id, TokenUse::Access, None, now, now + ttl)?;
+    claims.scope = scope.map(str::to_string);
+
+    let encoding_key =
+        EncodingKey::from_rsa_pem(private_key).map_err(|_| Error::JwtEncodeGenFail)?;
+
+    encode(&header(private_key)?, &claims, &encoding_key).map_err(|_| Error::JwtAccessGenFail)
+}
+
+/// Mints a refresh token in `family` for a session that started at `auth_time`,
+/// expiring at `exp`.
+pub fn gen_refresh_token(
+    user: &User,
+    app_id: &String,
+    scope: Option<&str>,
+    family: &str,
+    auth_time: usize,
+    exp: usize,
+    private_key: &[u8],
+) -> Result<String> {
+    let mut claims = Claims::new(
+        user,
+        app_id,
+        TokenUse::Refresh,
+        Some(family.to_string()),
+        auth_time,
+        exp,
+    )?;
+    claims.scope = scope.map(str::to_string);
+
+    let encoding_key =
+        EncodingKey::from_rsa_pem(private_key).map_err(|_| Error::JwtEncodeGenFail)?;
+
+    encode(&header(private_key)?, &claims, &encoding_key).map_err(|_| Error::JwtRefreshGenFail)
+}
+
+/// Mints a token for a confidential client acting on its own behalf.
+pub fn gen_service_token(app_id: &String, ttl: usize, private_key: &[u8]) -> Result<String> {
+    let claims = Claims::service(app_id, ttl)?;
 
     let encoding_key =
         EncodingKey::from_rsa_pem(private_key).map_err(|_| Error::JwtEncodeGenFail)?;
 
-    encode(&Header::new(Algorithm::RS256), &claims, &encoding_key)
-        .map_err(|_| Error::JwtAccessGenFail)
+    encode(&header(private_key)?, &claims, &encoding_key).map_err(|_| Error::JwtAccessGenFail)
 }
 
-pub fn gen_refresh_token(user: &User, app_id: &String, private_key: &[u8]) -> Result<String> {
-    let claims = Claims::new(&user, app_id, 60 * 60 * 24 * 3)?;
+/// Mints an OIDC ID token, describing the user's current profile if `profile` was granted.
+pub fn gen_id_token(
+    user: &User,
+    app_id: &String,
+    scope: Option<&str>,
+    nonce: Option<String>,
+    auth_time: usize,
+    ttl: usize,
+    private_key: &[u8],
+) -> Result<String> {
+    let now = now()?;
+
+    let claims = IdClaims {
+        iss: issuer(app_id),
+        sub: user.user_id.to_string(),
+        aud: app_id.to_string(),
+        iat: now,
+        exp: now + ttl,
+        auth_time,
+        nonce,
+        profile: scope_allows(scope, "profile").then(|| Profile::from_user(user)),
+    };
 
     let encoding_key =
         EncodingKey::from_rsa_pem(private_key).map_err(|_| Error::JwtEncodeGenFail)?;
 
-    encode(&Header::new(Algorithm::RS256), &claims, &encoding_key)
-        .map_err(|_| Error::JwtRefreshGenFail)
+    encode(&header(private_key)?, &claims, &encoding_key).map_err(|_| Error::JwtAccessGenFail)
+}
+
+/// Verifies a token against the app's published keys, picking the key by `kid`.
+/// Tokens minted before keys had ids are tried against every key, and those minted before
+/// `aud` only have to be signed by the app's key.
+pub fn verify_claims(token: &str, app_id: &str, public_keys: &[String]) -> Result<Claims> {
+    let kid = decode_header(token)
+        .map_err(|_| Error::JwtInvalidToken)?
+        .kid;
+
+    let candidates = public_keys.iter().filter(|key| match &kid {
+        Some(kid) => key_id(key.as_bytes()).is_ok_and(|id| &id == kid),
+        None => true,
+    });
+
+    let mut validation = Validation::new(Algorithm::RS256);
+    validation.validate_aud = false;
+
+    for public_key in candidates {
+        let decoding_key = DecodingKey::from_rsa_pem(public_key.as_bytes())
+            .map_err(|_| Error::JwtDecodeGenFail)?;
+
+        if let Ok(token_data) = decode::<Claims>(token, &decoding_key, &validation) {
+            let mut claims = token_data.claims;
+
+            if !claims.aud.is_empty() && claims.aud != app_id {
+                return Err(Error::JwtInvalidToken);
+            }
+
+            if claims.sub.is_empty() {
+                if let Some(user) = &claims.user {
+                    claims.sub = user.user_id.to_string();
+                }
+            }
+
+            return Ok(claims);
+        }
+    }
+
+    Err(Error::JwtInvalidToken)
+}
+
+pub fn verify_token(token: &str, app_id: &str, public_keys: &[String]) -> Result<User> {
+    // service tokens carry no user and can't stand in for one
+    verify_claims(token, app_id, public_keys)?
+        .user
+        .ok_or(Error::JwtInvalidToken)
+}
+
+pub fn issuer(app_id: &str) -> String {
+    format!("{}/api/auth/{app_id}", env::var("BASE_URL").unwrap())
+}
+
+/// Publishes an app's RSA public key as a JWK, using its RFC 7638 thumbprint as `kid`.
+pub fn public_jwk(public_key: &[u8]) -> Result<Jwk> {
+    let rsa = Rsa::public_key_from_pem(public_key).map_err(|_| Error::RsaPublicPEMFail)?;
+
+    let (n, e) = rsa_components(&rsa);
+
+    Ok(Jwk {
+        common: CommonParameters {
+            public_key_use: Some(PublicKeyUse::Signature),
+            key_algorithm: Some(KeyAlgorithm::RS256),
+            key_id: Some(thumbprint(&n, &e)),
+            ..Default::default()
+        },
+        algorithm: AlgorithmParameters::RSA(RSAKeyParameters {
+            key_type: RSAKeyType::RSA,
+            n,
+            e,
+        }),
+    })
 }
 
-pub fn verify_token(token: &str, public_key: &[u8]) -> Result<User> {
-    let decoding_key =
-        DecodingKey::from_rsa_pem(public_key).map_err(|_| Error::JwtDecodeGenFail)?;
+pub fn key_id(public_key: &[u8]) -> Result<String> {
+    let rsa = Rsa::public_key_from_pem(public_key).map_err(|_| Error::RsaPublicPEMFail)?;
 
-    let token_data = decode::<Claims>(token, &decoding_key, &Validation::new(Algorithm::RS256))
-        .map_err(|_| Error::JwtInvalidToken)?;
+    let (n, e) = rsa_components(&rsa);
 
-    Ok(token_data.claims.user)
+    Ok(thumbprint(&n, &e))
+}
+
+fn header(private_key: &[u8]) -> Result<Header> {
+    let rsa = Rsa::private_key_from_pem(private_key).map_err(|_| Error::RsaPrivatePEMFail)?;
+
+    let (n, e) = rsa_components(&rsa);
+
+    let mut header = Header::new(Algorithm::RS256);
+    header.kid = Some(thumbprint(&n, &e));
+
+    Ok(header)
+}
+
+fn rsa_components<T: HasPublic>(rsa: &Rsa<T>) -> (String, String) {
+    (
+        URL_SAFE_NO_PAD.encode(rsa.n().to_vec()),
+        URL_SAFE_NO_PAD.encode(rsa.e().to_vec()),
+    )
+}
+
+fn thumbprint(n: &str, e: &str) -> String {
+    let canonical = format!(r#"{{"e":"{e}","kty":"RSA","n":"{n}"}}"#);
+
+    URL_SAFE_NO_PAD.encode(sha256(canonical.as_bytes()))
+}
+
+#[derive(Serialize, Deserialize, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenUse {
+    #[default]
+    Access,
+    Refresh,
 }
 
 #[derive(Serialize, Deserialize)]
-struct Claims {
-    user: User,
-    app_id: String,
-    exp: usize,
+pub struct Claims {
+    #[serde(default)]
+    pub iss: String,
+    /// The `user_id` for user tokens, the client id for service tokens. Tokens from before
+    /// it only carry `user`, and get it filled in from there when verified.
+    #[serde(default)]
+    pub sub: String,
+    #[serde(default)]
+    pub aud: String,
+    #[serde(alias = "app_id")]
+    pub client_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<User>,
+    #[serde(default)]
+    pub token_use: TokenUse,
+    #[serde(default)]
+    pub jti: String,
+    /// Shared by every refresh token rotated from the same login.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub family: Option<String>,
+    /// Space separated scopes the token was granted, absent on tokens from before scopes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    #[serde(default)]
+    pub iat: usize,
+    /// When the user signed in, kept across refreshes to enforce the session lifetime.
+    #[serde(default)]
+    pub auth_time: usize,
+    pub exp: usize,
 }
 
 impl Claims {
-    fn new(user: &User, app_id: &String, exp: usize) -> Result<Self> {
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .map_err(|_| Error::JwtClaimsGenFail)?
-            .as_secs() as usize;
+    fn new(
+        user: &User,
+        app_id: &String,
+        token_use: TokenUse,
+        family: Option<String>,
+        auth_time: usize,
+        exp: usize,
+    ) -> Result<Self> {
+        Ok(Self {
+            iss: issuer(app_id),
+            sub: user.user_id.to_string(),
+            aud: app_id.to_string(),
+            client_id: app_id.to_string(),
+            user: Some(user.clone()),
+            token_use,
+            jti: gen_jti(),
+            family,
+            scope: None,
+            iat: now()?,
+            auth_time,
+            exp,
+        })
+    }
+
+    fn service(app_id: &String, exp: usize) -> Result<Self> {
+        let now = now()?;
 
         Ok(Self {
-            user: user.clone(),
-            app_id: app_id.to_string(),
+            iss: issuer(app_id),
+            sub: app_id.to_string(),
+            aud: app_id.to_string(),
+            client_id: app_id.to_string(),
+            user: None,
+            token_use: TokenUse::Access,
+            jti: gen_jti(),
+            family: None,
+            scope: None,
+            iat: now,
+            auth_time: now,
             exp: now + exp,
         })
     }
 }
+
+#[derive(Serialize)]
+struct IdClaims {
+    iss: String,
+    sub: String,
+    aud: String,
+    iat: usize,
+    exp: usize,
+    auth_time: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nonce: Option<String>,
+    #[serde(flatten)]
+    profile: Option<Profile>,
+}
+
+/// Standard OIDC profile claims, taken from the Discord account when linked, else Steam,
+/// else Google, else GitHub, else the first registered provider's.
+#[derive(Serialize)]
+pub struct Profile {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preferred_username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub picture: Option<String>,
+}
+
+impl Profile {
+    pub fn from_user(user: &User) -> Self {
+        let discord = &user.discord;
+        let steam = &user.steam;
+        let google = &user.google;
+        let github = &user.github;
+
+        // google, github and registered providers store the full picture url as the avatar
+        let picture = match (&discord.id, &discord.avatar, &steam.avatar) {
+            (Some(id), Some(avatar), _) => Some(format!(
+                "https://cdn.discordapp.com/avatars/{id}/{avatar}.png"
+            )),
+            (_, _, Some(avatar)) => {
+                Some(format!("https://avatars.steamstatic.com/{avatar}_full.jpg"))
+            }
+            _ => google.avatar.clone().or(github.avatar.clone()).or_else(|| {
+                user.identities
+                    .iter()
+                    .filter(|identity| !["discord", "steam"].contains(&identity.provider.as_str()))
+                    .find_map(|identity| identity.avatar.clone())
+            }),
+        };
+
+        let username = discord
+            .username
+            .clone()
+            .or(steam.username.clone())
+            .or(google.username.clone())
+            .or(github.username.clone())
+            .or_else(|| {
+                user.identities
+                    .iter()
+                    .find_map(|identity| identity.username.clone())
+            });
+
+        Self {
+            name: username.clone(),
+            preferred_username: username,
+            picture,
+        }
+    }
+}
+
+/// Whether a token's `scope` covers `name`, tokens from before scopes covering everything.
+pub fn scope_allows(scope: Option<&str>, name: &str) -> bool {
+    scope.is_none_or(|scope| scope.split(' ').any(|s| s == name))
+}
+
+pub fn gen_jti() -> String {
+    thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(24)
+        .map(char::from)
+        .collect()
+}
+
+pub fn now() -> Result<usize> {
+    Ok(SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|_| Error::JwtClaimsGenFail)?
+        .as_secs() as usize)
+}
@@ -1,18 +1,22 @@
-use std::{env, net::SocketAddr};
+use std::{env, net::SocketAddr, str::FromStr};
 
 use crate::error::Error;
 
 use self::state::AppState;
+use api::auth::merge::merge_accounts;
+use api::auth::profiles::{refresh_profiles_forever, refresh_stale_profiles};
 use axum::{
     http::Uri,
     middleware,
     response::{IntoResponse, Response},
     Json, Router,
 };
-use db::app::create_app;
+use db::app::{create_app, rotate_client_secret};
+use db::key::{retire_key, rotate_key};
 use dotenv::dotenv;
 use http::header::{AUTHORIZATION, CONTENT_TYPE};
 use serde_json::json;
+use sqlx::types::Uuid;
 use tower_cookies::CookieManagerLayer;
 use tower_http::cors::CorsLayer;
 
@@ -29,13 +33,45 @@ async fn main() {
 
     let args: Vec<String> = env::args().collect();
 
-    let state = AppState::new().await;
+    let mut state = AppState::new().await;
 
     if args.len() == 2 && args[1] == "init" {
         let app_id = create_app(&state.pg, "MAIN".to_string()).await.unwrap();
 
         println!("APP_ID: {app_id}");
+    } else if args.len() == 3 && args[1] == "rotate-key" {
+        let app_id = Uuid::from_str(&args[2]).unwrap();
+        let key_id = rotate_key(&state.pg, app_id).await.unwrap();
+
+        println!("KEY_ID: {key_id}");
+    } else if args.len() == 4 && args[1] == "retire-key" {
+        let app_id = Uuid::from_str(&args[2]).unwrap();
+        let key_id = args[3].parse::<i32>().unwrap();
+
+        retire_key(&state.pg, app_id, key_id).await.unwrap();
+
+        println!("RETIRED: {key_id}");
+    } else if args.len() == 3 && args[1] == "rotate-secret" {
+        let app_id = Uuid::from_str(&args[2]).unwrap();
+        let secret = rotate_client_secret(&state.pg, app_id).await.unwrap();
+
+        println!("CLIENT_SECRET: {secret}");
+    } else if args.len() == 5 && args[1] == "merge-users" {
+        let primary = args[3].parse::<i32>().unwrap();
+        let duplicate = args[4].parse::<i32>().unwrap();
+
+        merge_accounts(&mut state, &args[2], primary, duplicate)
+            .await
+            .unwrap();
+
+        println!("MERGED: {duplicate} -> {primary}");
+    } else if args.len() == 2 && args[1] == "refresh-profiles" {
+        let refreshed = refresh_stale_profiles(&mut state).await.unwrap();
+
+        println!("REFRESHED: {refreshed}");
     } else {
+        tokio::spawn(refresh_profiles_forever(state.clone()));
+
         let router = Router::new()
             .nest("/api", api::routes())
             .nest("/dashboard", dashboard::routes(state.clone()))
@@ -80,11 +116,15 @@ async fn main_response_mapper(uri: Uri, res: Response) -> Response {
     let error_response = client_status_error
         .as_ref()
         .map(|(status_code, client_error)| {
-            let client_error_body = json!({
-                "error": {
-                    "type": client_error
-                }
-            });
+            let client_error_body = if client_error.is_oauth() {
+                json!({ "error": client_error })
+            } else {
+                json!({
+                    "error": {
+                        "type": client_error
+                    }
+                })
+            };
 
             (*status_code, Json(client_error_body)).into_response()
         });
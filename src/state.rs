@@ -3,9 +3,13 @@ use std::env;
 use axum::extract::FromRef;
 use oauth2::{basic::BasicClient, AuthUrl, ClientId, ClientSecret, RedirectUrl, TokenUrl};
 
+use crate::api::auth::discord;
+
 #[derive(FromRef, Clone)]
 pub struct AppState {
     pub oauth: BasicClient,
+    #[from_ref(skip)]
+    pub github: Option<BasicClient>,
     pub redis: redis::Client,
     pub pg: sqlx::postgres::PgPool,
 }
@@ -14,6 +18,7 @@ impl AppState {
     pub async fn new() -> Self {
         Self {
             oauth: oauth_client(),
+            github: github_client(),
             redis: redis_client(),
             pg: sqlx_pool().await,
         }
@@ -21,13 +26,47 @@ impl AppState {
 }
 
 fn oauth_client() -> BasicClient {
-    let client_id = env::var("DISCORD_CLIENT_ID").unwrap();
-    let client_secret = env::var("DISCORD_CLIENT_SECRET").unwrap();
+    discord_client(
+        env::var("DISCORD_CLIENT_ID").unwrap(),
+        env::var("DISCORD_CLIENT_SECRET").unwrap(),
+    )
+}
+
+/// A client for a Discord application, the server's own or one an app registered.
+pub fn discord_client(client_id: String, client_secret: String) -> BasicClient {
     let redirect_url = format!("{}/auth/discord/redirect", env::var("BASE_URL").unwrap());
 
-    let auth_url = "https://discord.com/api/oauth2/authorize?response_type=code".to_string();
+    let auth_url = format!("{}/oauth2/authorize?response_type=code", discord::api_url());
+
+    let token_url = format!("{}/oauth2/token", discord::api_url());
+
+    BasicClient::new(
+        ClientId::new(client_id),
+        Some(ClientSecret::new(client_secret)),
+        AuthUrl::new(auth_url).unwrap(),
+        Some(TokenUrl::new(token_url).unwrap()),
+    )
+    .set_redirect_uri(RedirectUrl::new(redirect_url).unwrap())
+}
+
+/// `None` on servers without a GitHub OAuth app, where only apps with their own can use it.
+fn github_client() -> Option<BasicClient> {
+    Some(github_app_client(
+        env::var("GITHUB_CLIENT_ID").ok()?,
+        env::var("GITHUB_CLIENT_SECRET").ok()?,
+    ))
+}
+
+/// A client for a GitHub OAuth app, the server's own or one an app registered.
+pub fn github_app_client(client_id: String, client_secret: String) -> BasicClient {
+    let redirect_url = format!("{}/api/auth/github/redirect", env::var("BASE_URL").unwrap());
+
+    // overridable to sign in against a local stand-in
+    let auth_url = env::var("GITHUB_AUTH_URL")
+        .unwrap_or("https://github.com/login/oauth/authorize".to_string());
 
-    let token_url = "https://discord.com/api/oauth2/token".to_string();
+    let token_url = env::var("GITHUB_TOKEN_URL")
+        .unwrap_or("https://github.com/login/oauth/access_token".to_string());
 
     BasicClient::new(
         ClientId::new(client_id),